@@ -0,0 +1,291 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tracing::{event, Level};
+
+/// Something worth notifying the user about, passed to hooks as a line of JSON.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum HookEvent {
+    /// A message with `@username` in it.
+    Mention {
+        id: Option<i64>,
+        timestamp: Option<DateTime<Utc>>,
+        from: String,
+        text: String,
+    },
+    /// A message starting with `@username`, addressed to the user rather than just mentioning them.
+    Direct {
+        id: Option<i64>,
+        timestamp: Option<DateTime<Utc>>,
+        from: String,
+        text: String,
+    },
+    /// A received file or photo, after it was saved to `path`.
+    File {
+        id: Option<i64>,
+        timestamp: Option<DateTime<Utc>>,
+        from: String,
+        name: String,
+        mime: String,
+        path: PathBuf,
+    },
+}
+
+impl HookEvent {
+    /// The event for a text message from `from` to a user called `username`, if it mentions them.
+    pub fn for_text(
+        username: &str,
+        from: &str,
+        text: &str,
+        id: Option<i64>,
+        timestamp: Option<DateTime<Utc>>,
+    ) -> Option<HookEvent> {
+        // users aren't notified about their own messages, which the server sends back
+        if username.is_empty() || from == username {
+            return None;
+        }
+        let (from, text) = (from.to_string(), text.to_string());
+        if mention_at(&text, username, 0) {
+            return Some(HookEvent::Direct { id, timestamp, from, text });
+        }
+        let mentioned = text.match_indices('@').any(|(i, _)| mention_at(&text, username, i));
+        mentioned.then_some(HookEvent::Mention { id, timestamp, from, text })
+    }
+}
+
+/// Whether `text` has `@username` at byte `at`, as a whole word.
+fn mention_at(text: &str, username: &str, at: usize) -> bool {
+    let Some(rest) = text[at..].strip_prefix('@') else {
+        return false;
+    };
+    match rest.get(..username.len()) {
+        Some(name) if name.eq_ignore_ascii_case(username) => !rest[username.len()..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+/// Where events are sent, a command run for each event, and/or a FIFO another program reads from.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    command: Option<String>,
+    fifo: Option<PathBuf>,
+}
+
+impl Hooks {
+    pub fn new(command: Option<String>, fifo: Option<PathBuf>) -> Self {
+        Hooks { command, fifo }
+    }
+
+    /// Send `event` to the hooks, without waiting for them.
+    ///
+    /// Failures are only logged, a broken hook shouldn't stop the client.
+    pub fn run(&self, event: &HookEvent) {
+        if self.command.is_none() && self.fifo.is_none() {
+            return;
+        }
+        if let Some(fifo) = &self.fifo {
+            match fifo_line(event, PIPE_BUF) {
+                Some(line) => {
+                    if let Err(e) = write_fifo(fifo, &line) {
+                        event!(Level::WARN, "Failed to write event to {}: {e}", fifo.display());
+                    }
+                }
+                None => event!(Level::WARN, "Dropped event too long for {}.", fifo.display()),
+            }
+        }
+        if let Some(command) = &self.command {
+            tokio::spawn(run_command(command.clone(), to_line(event)));
+        }
+    }
+}
+
+/// The most that can be written to a FIFO in one go, so readers never see part of an event, or two mixed together.
+#[cfg(unix)]
+const PIPE_BUF: usize = libc::PIPE_BUF;
+#[cfg(not(unix))]
+const PIPE_BUF: usize = 4096;
+
+/// `event` as a line of JSON.
+fn to_line(event: &HookEvent) -> String {
+    // only fails for maps with non-string keys
+    let mut line = serde_json::to_string(event).expect("Failed to serialize hook event...");
+    line.push('\n');
+    line
+}
+
+/// `event` as a line of JSON at most `max_len` bytes long, with the message text cut short and ending in "…" if needs be.
+///
+/// `None` if that's not enough, e.g. for a file with a very long name.
+fn fifo_line(event: &HookEvent, max_len: usize) -> Option<String> {
+    let line = to_line(event);
+    if line.len() <= max_len {
+        return Some(line);
+    }
+    let (HookEvent::Mention { text, .. } | HookEvent::Direct { text, .. }) = event else {
+        return None;
+    };
+    let cut_at = |end: usize| {
+        let mut short = event.clone();
+        if let HookEvent::Mention { text: short_text, .. } | HookEvent::Direct { text: short_text, .. } = &mut short {
+            *short_text = format!("{}…", &text[..end]);
+        }
+        to_line(&short)
+    };
+    // escaping makes the line grow unevenly with the text, so search for the longest text that fits
+    let ends: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let fitting = ends.partition_point(|&end| cut_at(end).len() <= max_len);
+    fitting.checked_sub(1).map(|last| cut_at(ends[last]))
+}
+
+/// Write `line` to a FIFO, dropping it if nothing is reading, rather than waiting for a reader.
+///
+/// `line` should be at most `PIPE_BUF` bytes, see `fifo_line`, longer writes can be split up between readers.
+///
+/// Other platforms have no FIFOs, `fifo` can be any file that takes writes there, like a named pipe on Windows.
+fn write_fifo(fifo: &Path, line: &str) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    // opening a FIFO for writing blocks until there's a reader, unless it's non-blocking
+    #[cfg(unix)]
+    options.custom_flags(libc::O_NONBLOCK);
+    let mut file = options.open(fifo)?;
+    file.write_all(line.as_bytes())
+}
+
+/// The shell hook commands are run with, and the flag that passes it a command.
+#[cfg(unix)]
+const SHELL: (&str, &str) = ("sh", "-c");
+#[cfg(windows)]
+const SHELL: (&str, &str) = ("cmd", "/C");
+
+/// Run `command` with the shell, with `line` on its stdin.
+async fn run_command(command: String, line: String) {
+    let (shell, flag) = SHELL;
+    let child = Command::new(shell)
+        .arg(flag)
+        .arg(&command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            event!(Level::WARN, "Failed to run hook \"{command}\": {e}");
+            return;
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // the hook may not read its input, which is fine
+        let _ = stdin.write_all(line.as_bytes()).await;
+    }
+    match child.wait().await {
+        Ok(status) if !status.success() => event!(Level::WARN, "Hook \"{command}\" failed: {status}"),
+        Err(e) => event!(Level::WARN, "Failed to wait for hook \"{command}\": {e}"),
+        Ok(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::time::Duration;
+
+    use crate::hooks::{self, HookEvent, Hooks};
+
+    fn text_event(text: &str) -> Option<HookEvent> {
+        HookEvent::for_text("bob", "alice", text, Some(1), None)
+    }
+
+    #[test]
+    fn test_for_text() {
+        assert!(matches!(text_event("@bob are you there?"), Some(HookEvent::Direct { .. })));
+        assert!(matches!(text_event("@Bob: hi"), Some(HookEvent::Direct { .. })));
+        assert!(matches!(text_event("ask @bob"), Some(HookEvent::Mention { .. })));
+        assert!(matches!(text_event("thanks @bob!"), Some(HookEvent::Mention { .. })));
+        assert_eq!(text_event("@bobby hi"), None);
+        assert_eq!(text_event("hi bob"), None);
+        assert_eq!(text_event("@"), None);
+        // own messages, and anonymous users, are never notified
+        assert_eq!(HookEvent::for_text("bob", "bob", "@bob", None, None), None);
+        assert_eq!(HookEvent::for_text("", "alice", "@ hi", None, None), None);
+    }
+
+    #[test]
+    fn test_serialize() -> Result<(), Box<dyn Error>> {
+        let event = text_event("@bob hi").unwrap();
+        assert_eq!(
+            serde_json::to_string(&event)?,
+            r#"{"event":"direct","id":1,"timestamp":null,"from":"alice","text":"@bob hi"}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fifo_line() -> Result<(), Box<dyn Error>> {
+        let short = text_event("@bob hi").unwrap();
+        assert_eq!(hooks::fifo_line(&short, 4096), Some(hooks::to_line(&short)));
+
+        // quotes are escaped, so the JSON is longer than the text
+        let text = format!("@bob {}", "\"é".repeat(3000));
+        let line = hooks::fifo_line(&text_event(&text).unwrap(), 4096).unwrap();
+        assert!(line.len() <= 4096 && line.ends_with('\n'));
+        let event: serde_json::Value = serde_json::from_str(&line)?;
+        let cut = event["text"].as_str().unwrap().strip_suffix('…').unwrap();
+        assert!(text.starts_with(cut) && cut.len() > 3000);
+
+        let file = HookEvent::File {
+            id: None,
+            timestamp: None,
+            from: "alice".to_string(),
+            name: "a".repeat(5000),
+            mime: "text/plain".to_string(),
+            path: "files/a".into(),
+        };
+        assert_eq!(hooks::fifo_line(&file, 4096), None);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_run_command() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("event.json");
+        let hooks = Hooks::new(Some(format!("cat > {}", output.display())), None);
+        hooks.run(&text_event("ask @bob").unwrap());
+
+        for _ in 0..100 {
+            if std::fs::read_to_string(&output).is_ok_and(|event| event.ends_with('\n')) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let event: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&output)?)?;
+        assert_eq!(event["event"], "mention");
+        assert_eq!(event["from"], "alice");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_fifo_without_reader() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let fifo = dir.path().join("events");
+        let path = std::ffi::CString::new(fifo.to_string_lossy().as_bytes())?;
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        // doesn't block waiting for a reader
+        assert!(crate::hooks::write_fifo(&fifo, "{}\n").is_err());
+        Ok(())
+    }
+}
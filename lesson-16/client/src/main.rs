@@ -1,48 +1,119 @@
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
-use std::fs::{create_dir_all, File};
-use std::process::exit;
+use std::fs::create_dir_all;
+use std::io::IsTerminal;
+use std::path::PathBuf;
 use std::time::Duration;
 
 use chrono::Utc;
 use clap::Parser;
-use tokio::io;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::watch;
+use tokio_stream::StreamExt;
 use tracing::{event, Level};
-use tracing_subscriber::{prelude::*, Registry};
-use tracing_subscriber::filter::LevelFilter;
 
-use rust_chat::{Message, UserMessage};
+use rust_chat::config::{Loader, Validate};
+use rust_chat::logging::{self, LogArgs};
+use rust_chat::commands::{self, ClientCommand};
+use rust_chat::client::ChatClient;
+use rust_chat::{storage, Message};
 
+use crate::hooks::{HookEvent, Hooks};
+use crate::preview::ImagePreview;
+use crate::script::ScriptCommand;
+use crate::ui::Ui;
+
+mod hooks;
+mod preview;
+mod script;
+mod terminal;
+mod tui;
+mod ui;
+
+/// Number of received messages kept around for quoting replies.
+const RECENT_MESSAGES: usize = 1000;
+
+/// Exit code when the server can't be reached. An invalid config exits with 2, see `ConfigError::exit`.
+const EXIT_CONNECT_FAILED: i32 = 1;
+/// Exit code when the connection to the server is lost.
+const EXIT_CONNECTION_LOST: i32 = 3;
+
+/// How often to check the config file for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Client config, from the config file, `CHAT_CLIENT_*` environment variables, and the command line.
 #[derive(Parser, Debug)]
-struct Args {
+struct Config {
+    /// TOML config file, with settings named after the arguments, e.g. `address = "10.0.0.1"`.
+    #[arg(long)]
+    config: Option<PathBuf>,
     #[arg(short, long, default_value_t = String::from("127.0.0.1"))]
     address: String,
     #[arg(short, long, default_value_t = 11111)]
     port: u16,
-    #[arg(long, default_value = "info")]
-    loglevel: LevelFilter,
+    #[command(flatten)]
+    log: LogArgs,
     #[arg(long, default_value_t = String::from("client.log"))]
     logfile: String,
+    /// Print messages line by line and read plain lines of input, instead of the full screen interface.
+    #[arg(long)]
+    plain: bool,
+    /// Read commands as lines of JSON and print received messages as lines of JSON, for scripts and bots.
+    #[arg(long, visible_alias = "script")]
+    json: bool,
+    /// Preview received images in the terminal. The full screen interface always uses `blocks`.
+    #[arg(long, value_enum, default_value_t = ImagePreview::Off)]
+    image_preview: ImagePreview,
+    /// Widest image preview, in terminal columns.
+    #[arg(long, default_value_t = 40)]
+    image_preview_width: u32,
+    /// Shell command run for each mention, direct message or received file, with the event as JSON on its stdin.
+    #[arg(long)]
+    hook_command: Option<String>,
+    /// FIFO to write each event to as a line of JSON, events are dropped while nothing is reading it.
+    #[arg(long)]
+    hook_fifo: Option<PathBuf>,
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, value) in [("address", &self.address), ("logfile", &self.logfile)] {
+            if value.trim().is_empty() {
+                problems.push(format!("{name} must not be empty"));
+            }
+        }
+        if self.image_preview_width == 0 {
+            problems.push(String::from("image_preview_width must be greater than 0"));
+        }
+        problems
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+    let loader = Loader::new("CHAT_CLIENT_");
+    let mut config: Config = loader.load().unwrap_or_else(|e| e.exit());
     let local_path = env::current_dir().unwrap();
+    // the full screen interface needs a terminal, and log lines on stdout would be drawn over it
+    let plain = config.plain || config.json || !std::io::stdout().is_terminal() || !std::io::stdin().is_terminal();
+    // stdout is only for messages in json mode
+    if !plain || config.json {
+        config.log.no_log_stdout = true;
+    }
 
-    // initialize logging
-    let log_subscriber = Registry::default().with({
-        let file =
-            File::create(local_path.join(args.logfile)).expect("Failed to create logfile...");
-        tracing_subscriber::fmt::layer()
-            .with_writer(file)
-            .with_filter(args.loglevel)
-    });
-    tracing::subscriber::set_global_default(log_subscriber)
-        .expect("Unable to set global subscriber...");
+    let log = logging::init(&config.log, &local_path.join(&config.logfile), "client").expect("Failed to set up logging...");
+    // only the log level can change while running, everything else is used once on startup
+    loader
+        .watch(CONFIG_POLL_INTERVAL, move |reloaded: Result<Config, _>| match reloaded {
+            Ok(reloaded) => {
+                if let Err(e) = log.set_filter(&reloaded.log.loglevel) {
+                    event!(Level::ERROR, "Failed to change log level: {e}");
+                }
+            }
+            Err(e) => event!(Level::ERROR, "Ignoring invalid config: {e}"),
+        })
+        .unwrap_or_else(|e| e.exit());
 
     let files_path = local_path.join("files");
     let images_path = files_path.join("images");
@@ -52,155 +123,225 @@ async fn main() -> Result<(), Box<dyn Error>> {
     create_dir_all(images_path.clone()).expect("Failed to create directories to store files...");
     event!(Level::INFO, "Directories created...");
 
-    let bind_addr = format!("{}:{}", args.address, args.port);
+    let bind_addr = format!("{}:{}", config.address, config.port);
 
-    println!("Connecting to chat channel...");
+    let image_preview = match config.image_preview.resolve() {
+        _ if config.json => ImagePreview::Off,
+        ImagePreview::Off => ImagePreview::Off,
+        _ if !plain => ImagePreview::Blocks,
+        preview => preview,
+    };
+
+    let (ui, mut input) = if config.json { Ui::start_json() } else { Ui::start(plain)? };
+    ui.message("Connecting to chat channel...");
     event!(
         Level::INFO,
         "Connecting to server on {bind_addr}",
     );
 
-    // create stream and synchronization channel
-    let stream = TcpStream::connect(bind_addr).await?;
-    let (tx, mut rx) = mpsc::channel::<UserMessage>(2048);
+    let client = match ChatClient::connect(&bind_addr).await {
+        Ok(client) => client,
+        // the error wouldn't be visible on the alternate screen
+        Err(e) => ui.exit_with_error(EXIT_CONNECT_FAILED, &e.to_string()),
+    };
+    let (mut sender, mut messages) = client.split();
 
-    println!("Please enter a username (leave blank for anon mode): ");
-    let input = read_input().await.expect("Failed to read input...");
-    let mut user_name = if input.trim().is_empty() {
-        println!("Connected to chat anonymously (spooky)");
+    // scripts set their username with a command instead
+    let name = if config.json {
+        String::new()
+    } else {
+        ui.message("Please enter a username (leave blank for anon mode): ");
+        let Some(name) = input.recv().await else {
+            ui.exit(0);
+        };
+        name
+    };
+    let user_name = if name.trim().is_empty() {
+        ui.message("Connected to chat anonymously (spooky)");
         None
     } else {
-        println!("Connected to chat with username: {input}");
-        Some(input)
+        ui.message(format!("Connected to chat with username: {name}"));
+        Some(name)
     };
+    // the current username, for spotting mentions in received messages
+    let (user_name_tx, user_name_rx) = watch::channel(user_name.clone());
+    sender.set_username(user_name);
+    let hooks = Hooks::new(config.hook_command.clone(), config.hook_fifo.clone());
+    let json = config.json;
     // handle user input
+    let input_ui = ui.clone();
     tokio::spawn(async move {
-        loop {
-            // unrecoverable
-            let input = read_input().await.expect("Failed to read input...");
+        let ui = input_ui;
+        // input only ends once stdin is closed
+        while let Some(input) = input.recv().await {
             event!(Level::INFO, "Got input: \"{input}\"");
 
-            let message = Message::try_from(input).unwrap();
+            let parsed = if json {
+                ScriptCommand::parse(&input).map_err(|e| format!("Invalid command: {e}"))
+            } else {
+                input.parse::<ClientCommand>().map_err(|e| e.to_string())
+            };
+            let command = match parsed {
+                Ok(command) => command,
+                Err(e) => {
+                    ui.message(e);
+                    continue;
+                }
+            };
 
-            match message {
-                Message::SetUser { username} => {
-                    user_name = username
+            match command {
+                ClientCommand::SetUser { username } => {
+                    user_name_tx.send_replace(username.clone());
+                    sender.set_username(username);
                 }
-                Message::Stop => {
+                ClientCommand::Help { command } => match commands::help(command.as_deref()) {
+                    Ok(help) => help.lines().for_each(|line| ui.message(line)),
+                    Err(e) => ui.message(e.to_string()),
+                },
+                ClientCommand::Stop => {
                     event!(Level::INFO, "Received stop message, stopping...");
-                    exit(0);
-                }
-                m => {
-                    // unrecoverable
-                    tx.send(UserMessage{message: m, username: user_name.clone()})
-                        .await
-                        .expect("Failed to send message to server...");
+                    ui.exit(0);
                 }
+                command => match command.into_message(sender.username().map(String::from)).await {
+                    Ok(Some(message)) => {
+                        event!(Level::INFO, "Sending message to server...");
+                        if let Err(e) = sender.send_message(&message).await {
+                            event!(Level::ERROR, "{e}");
+                            ui.exit_with_error(EXIT_CONNECTION_LOST, &e.to_string());
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => ui.message(e.to_string()),
+                },
             }
         }
+        ui.exit(0);
     });
 
-    // server stream handler, send/receive
-    loop {
-        // set which types of stream events the client is interested in
-        let ready = stream
-            .ready(io::Interest::READABLE | io::Interest::WRITABLE)
-            .await?;
-
-        // check if stream is ready to be read, non-blocking
-        if ready.is_readable() {
-            let mut msg_length_raw = [0u8; 4];
-            let read_result = stream.try_read(&mut msg_length_raw);
-
-            if read_result.is_err() {
-                let result = read_result.err().unwrap();
-                if result.kind() == io::ErrorKind::WouldBlock {
-                    continue;
-                }
-                event!(Level::ERROR, "Failed to read from server...");
-                exit(0);
-            }
-
-            let msg_length = u32::from_le_bytes(msg_length_raw);
-            let mut msg_raw = vec![
-                0u8;
-                usize::try_from(msg_length)
-                    .expect("Failed to parse message length from server...")
-            ];
-
-            stream
-                .try_read(&mut msg_raw)
-                .expect("Failed to read message from server...");
+    // recent messages, used to quote the message being replied to
+    let mut recent: BTreeMap<i64, String> = BTreeMap::new();
 
-            let message_result = serde_cbor::from_slice::<UserMessage>(&msg_raw);
-
-            if message_result.is_err() {
-                event!(Level::INFO, "Server disconnected...");
-                exit(0);
+    while let Some(received) = messages.next().await {
+        let msg = match received {
+            Ok(msg) => msg,
+            Err(e) => {
+                event!(Level::ERROR, "{e}");
+                ui.exit_with_error(EXIT_CONNECTION_LOST, &e.to_string());
             }
-
-            let msg = message_result.unwrap();
-            let message = msg.message;
-            let username = msg.username.unwrap_or(String::from("Anonymous"));
-
-            match message {
-                Message::File { name, data } => {
-                    println!("Receiving file from \"{username}\": {name}...");
-                    event!(Level::INFO, "Receiving file: {name}...");
-                    tokio::fs::write(files_path.clone().join(name), data)
-                        .await
-                        .expect("Failed to write received file...");
-                }
-                Message::Photo { data } => {
-                    println!("Receiving photo from \"{username}\"...");
-                    event!(Level::INFO, "Receiving photo from \"{username}\"...");
-                    let timestamp = Utc::now();
-                    tokio::fs::write(
-                        images_path
-                            .clone()
-                            .join(format!("{}.png", timestamp.timestamp())),
-                        data,
-                    )
-                    .await
-                    .expect("Failed to write received photo...");
+        };
+        if json {
+            let saved = match &msg.message {
+                Message::File { name, data, .. } => Some(storage::write_new(&files_path, name, data)),
+                Message::Photo { data } => Some(storage::write_new(&images_path, &photo_name(), data)),
+                _ => None,
+            };
+            let path = match &saved {
+                Some(Ok(path)) => Some(path.as_path()),
+                Some(Err(e)) => {
+                    event!(Level::ERROR, "Failed to save received {}: {e}", msg.message.kind());
+                    ui.message(format!("Failed to save received {}: {e}", msg.message.kind().to_lowercase()));
+                    None
                 }
-                Message::Text(msg) => {
-                    println!("[{username}]: {msg}");
-                    event!(Level::INFO, "Received message from \"{username}\": \"{msg}\"");
-                }
-                _ => {}
-            }
+                None => None,
+            };
+            ui.json(&msg, path);
+            continue;
         }
+        let msg_id = msg.id;
+        let sent_at = msg.timestamp;
+        let reply_to = msg.reply_to;
+        let id = msg.id.map(|id| format!("[#{id}]")).unwrap_or_default();
+        let message = msg.message;
+        let username = msg.username.unwrap_or(String::from("Anonymous"));
+        ui.user(&username);
 
-        // check if any data needs to be sent
-        let send_data = rx.try_recv();
-        if let Ok(data) = send_data {
-            loop {
-                let msg_serialized =
-                    serde_cbor::to_vec(&data).expect("Failed to serialize message for server...");
-                let msg_length = msg_serialized.len() as u32;
-
-                event!(Level::INFO, "Sending message to server...");
-                let write_res = stream.try_write(&msg_length.to_le_bytes());
-                if write_res.is_err() {
-                    let result = write_res.err().unwrap();
-                    if result.kind() == io::ErrorKind::WouldBlock {
+        match message {
+            Message::File { name, mime, data } => {
+                event!(Level::INFO, "Receiving file: {name} ({mime}, {} bytes)...", data.len());
+                let path = match storage::write_new(&files_path, &name, &data) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        event!(Level::ERROR, "Failed to save file {name}: {e}");
+                        ui.notice(format!("{id}Received file from \"{username}\": {name} ({mime}), but failed to save it: {e}"));
                         continue;
                     }
-                    return Err(result.into());
+                };
+                ui.notice(format!(
+                    "{id}Received file from \"{username}\": {name} ({mime}), saved to {}",
+                    path.display()
+                ));
+                hooks.run(&HookEvent::File { id: msg_id, timestamp: sent_at, from: username, name, mime, path });
+            }
+            Message::Photo { data } => {
+                event!(Level::INFO, "Receiving photo from \"{username}\"...");
+                let name = photo_name();
+                let path = match storage::write_new(&images_path, &name, &data) {
+                    Ok(path) => path,
+                    Err(e) => {
+                        event!(Level::ERROR, "Failed to save photo {name}: {e}");
+                        ui.notice(format!("{id}Received photo from \"{username}\", but failed to save it: {e}"));
+                        continue;
+                    }
+                };
+                ui.notice(format!("{id}Received photo from \"{username}\", saved to {}", path.display()));
+                hooks.run(&HookEvent::File {
+                    id: msg_id,
+                    timestamp: sent_at,
+                    from: username,
+                    name: path.file_name().unwrap_or_default().to_string_lossy().to_string(),
+                    mime: String::from("image/png"),
+                    path,
+                });
+                if image_preview != ImagePreview::Off {
+                    let ui = ui.clone();
+                    let columns = config.image_preview_width;
+                    // decoding is slow for large images, so keep it off the async threads
+                    tokio::task::spawn_blocking(move || {
+                        if let Err(e) = ui.image(&data, image_preview, columns) {
+                            event!(Level::WARN, "Failed to preview photo: {e}");
+                        }
+                    });
+                }
+            }
+            Message::Text(msg) => {
+                if let Some(parent) = reply_to {
+                    match recent.get(&parent) {
+                        Some(quote) => ui.message(format!("  > {quote}")),
+                        None => ui.message(format!("  > #{parent}")),
+                    }
+                }
+                ui.message(format!("{id}[{username}]: {msg}"));
+                event!(Level::INFO, "Received message from \"{username}\": \"{msg}\"");
+                let me = user_name_rx.borrow().clone().unwrap_or_default();
+                if let Some(event) = HookEvent::for_text(&me, &username, &msg, msg_id, sent_at) {
+                    hooks.run(&event);
                 }
-                stream
-                    .try_write(&msg_serialized)
-                    .expect("Failed to send message to server...");
-                break;
+                if let Some(msg_id) = msg_id {
+                    recent.insert(msg_id, format!("[{username}]: {msg}"));
+                    if recent.len() > RECENT_MESSAGES {
+                        recent.pop_first();
+                    }
+                }
+            }
+            Message::Edit { id, new_text } => {
+                if let Some(quote) = recent.get_mut(&id) {
+                    *quote = format!("[{username}]: {new_text}");
+                }
+                ui.message(format!("[{username}] edited #{id}: {new_text}"));
+                event!(Level::INFO, "Received edit of #{id} from \"{username}\": \"{new_text}\"");
+            }
+            Message::Delete { id } => {
+                recent.remove(&id);
+                ui.message(format!("[{username}] deleted #{id}"));
+                event!(Level::INFO, "Received delete of #{id} from \"{username}\"");
             }
         }
-        tokio::time::sleep(Duration::from_millis(1)).await;
     }
+    event!(Level::INFO, "Server disconnected...");
+    ui.exit_with_error(EXIT_CONNECTION_LOST, "The server closed the connection.");
 }
 
-async fn read_input() -> Result<String, Box<dyn Error>> {
-    let mut user_input = String::new();
-    BufReader::new(io::stdin()).read_line(&mut user_input).await?;
-    Ok(user_input.trim().to_string())
+/// Received photos are named after when they arrived, `storage::write_new` numbers any that arrive in the same second.
+fn photo_name() -> String {
+    format!("{}.png", Utc::now().timestamp())
 }
@@ -0,0 +1,274 @@
+use std::collections::BTreeMap;
+use std::env;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, RgbaImage};
+
+/// Rough width of a terminal cell in pixels, used to size sixel images, which are drawn in pixels rather than cells.
+const CELL_WIDTH_PX: u32 = 10;
+/// Largest chunk of base64 data in a single kitty graphics escape.
+const KITTY_CHUNK: usize = 4096;
+/// Rows in a sixel band.
+const SIXEL_BAND: u32 = 6;
+
+/// How received images are previewed in the terminal.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImagePreview {
+    /// Only save images, without previewing them.
+    Off,
+    /// Pick a protocol the terminal supports, falling back to `blocks`.
+    Auto,
+    /// The kitty graphics protocol, also supported by WezTerm and Ghostty.
+    Kitty,
+    /// iTerm2 inline images, also supported by WezTerm.
+    Iterm,
+    /// Sixel graphics, supported by foot, mlterm, xterm with `-ti vt340` and others.
+    Sixel,
+    /// Unicode half blocks in 24 bit colour, which works in most terminals.
+    Blocks,
+}
+
+impl ImagePreview {
+    /// Turn `Auto` into the protocol to use, based on the environment variables terminals set.
+    pub fn resolve(self) -> ImagePreview {
+        if self != ImagePreview::Auto {
+            return self;
+        }
+        let var = |name| env::var(name).unwrap_or_default();
+        detect(&var("TERM"), &var("TERM_PROGRAM"), env::var_os("KITTY_WINDOW_ID").is_some())
+    }
+}
+
+fn detect(term: &str, term_program: &str, kitty_window: bool) -> ImagePreview {
+    if kitty_window || term.contains("kitty") || term_program == "ghostty" {
+        ImagePreview::Kitty
+    } else if matches!(term_program, "iTerm.app" | "WezTerm") {
+        ImagePreview::Iterm
+    } else if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") {
+        ImagePreview::Sixel
+    } else {
+        ImagePreview::Blocks
+    }
+}
+
+/// Escape codes to draw an image, encoded as `png`, about `columns` cells wide.
+///
+/// `preview` should already be resolved, `Off` and `Auto` give an empty string.
+pub fn render(png: &[u8], preview: ImagePreview, columns: u32) -> Result<String, ImageError> {
+    Ok(match preview {
+        ImagePreview::Off | ImagePreview::Auto => String::new(),
+        ImagePreview::Kitty => kitty(png, columns),
+        ImagePreview::Iterm => format!(
+            "\x1b]1337;File=inline=1;size={};width={columns};preserveAspectRatio=1:{}\x07\n",
+            png.len(),
+            STANDARD.encode(png)
+        ),
+        ImagePreview::Sixel => sixel(&image::load_from_memory(png)?, columns * CELL_WIDTH_PX),
+        ImagePreview::Blocks => blocks(&image::load_from_memory(png)?, columns)
+            .iter()
+            .map(|row| format!("{row}\n"))
+            .collect(),
+    })
+}
+
+/// The kitty graphics protocol takes the PNG as is, split into chunks.
+fn kitty(png: &[u8], columns: u32) -> String {
+    let encoded = STANDARD.encode(png);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK).collect();
+    let mut out = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(i + 1 < chunks.len());
+        if i == 0 {
+            out.push_str(&format!("\x1b_Ga=T,f=100,c={columns},m={more};"));
+        } else {
+            out.push_str(&format!("\x1b_Gm={more};"));
+        }
+        // base64 is ascii
+        out.push_str(std::str::from_utf8(chunk).unwrap());
+        out.push_str("\x1b\\");
+    }
+    out.push('\n');
+    out
+}
+
+/// Scale `image` down to at most `width` pixels wide, keeping its aspect ratio.
+///
+/// `pixel_aspect` is the height of a pixel relative to its width, two for half blocks, which are twice as tall as wide.
+fn scale(image: &DynamicImage, width: u32, pixel_aspect: u32) -> RgbaImage {
+    let width = width.min(image.width()).max(1);
+    let height = (u64::from(image.height()) * u64::from(width) / u64::from(image.width().max(1))) as u32;
+    image
+        .resize_exact(width, (height / pixel_aspect).max(1) * pixel_aspect, FilterType::Triangle)
+        .to_rgba8()
+}
+
+/// Blend a pixel onto black, as terminals have no transparency.
+fn rgb(pixel: &image::Rgba<u8>) -> [u8; 3] {
+    let [r, g, b, a] = pixel.0;
+    let blend = |c: u8| (u16::from(c) * u16::from(a) / 255) as u8;
+    [blend(r), blend(g), blend(b)]
+}
+
+/// A `▀` cell of a half block preview, the colours of its top and bottom pixels.
+pub type HalfBlock = ([u8; 3], [u8; 3]);
+
+/// Scale `image` to at most `columns` cells wide, as rows of half blocks, two pixels to a cell.
+///
+/// Each row is exactly as wide as the scaled image.
+pub fn half_blocks(image: &DynamicImage, columns: u32) -> Vec<Vec<HalfBlock>> {
+    let scaled = scale(image, columns, 2);
+    (0..scaled.height() / 2)
+        .map(|row| {
+            (0..scaled.width())
+                .map(|x| (rgb(scaled.get_pixel(x, row * 2)), rgb(scaled.get_pixel(x, row * 2 + 1))))
+                .collect()
+        })
+        .collect()
+}
+
+/// Draw `image` at most `columns` cells wide, as rows of `▀`, with the top pixel as the foreground and the bottom as the background.
+fn blocks(image: &DynamicImage, columns: u32) -> Vec<String> {
+    half_blocks(image, columns)
+        .iter()
+        .map(|row| {
+            let mut line = String::new();
+            for ([tr, tg, tb], [br, bg, bb]) in row {
+                line.push_str(&format!("\x1b[38;2;{tr};{tg};{tb}m\x1b[48;2;{br};{bg};{bb}m▀"));
+            }
+            line.push_str("\x1b[0m");
+            line
+        })
+        .collect()
+}
+
+/// Encode `image` at most `width` pixels wide as sixels, with colours from a 6x6x6 colour cube.
+fn sixel(image: &DynamicImage, width: u32) -> String {
+    let scaled = scale(image, width, 1);
+    let (width, height) = scaled.dimensions();
+    let index = |x, y| {
+        let [r, g, b] = rgb(scaled.get_pixel(x, y));
+        let level = |c: u8| (u16::from(c) * 5 + 127) / 255;
+        level(r) * 36 + level(g) * 6 + level(b)
+    };
+
+    let mut out = format!("\x1bPq\"1;1;{width};{height}");
+    for color in 0..216u16 {
+        // levels as percentages, which is what sixel colours are given in
+        let percent = |level: u16| level * 20;
+        out.push_str(&format!(
+            "#{color};2;{};{};{}",
+            percent(color / 36),
+            percent(color / 6 % 6),
+            percent(color % 6)
+        ));
+    }
+    for band in (0..height).step_by(SIXEL_BAND as usize) {
+        // the six bit columns of each colour in this band
+        let mut colors: BTreeMap<u16, Vec<u8>> = BTreeMap::new();
+        for y in band..(band + SIXEL_BAND).min(height) {
+            for x in 0..width {
+                colors.entry(index(x, y)).or_insert_with(|| vec![0; width as usize])[x as usize] |= 1 << (y - band);
+            }
+        }
+        for (color, bits) in colors {
+            out.push_str(&format!("#{color}"));
+            push_sixels(&mut out, &bits);
+            // back to the start of the band for the next colour
+            out.push('$');
+        }
+        out.push('-');
+    }
+    out.push_str("\x1b\\\n");
+    out
+}
+
+/// Append sixel characters for `bits`, with runs of the same character shortened to `!<count><char>`.
+fn push_sixels(out: &mut String, bits: &[u8]) {
+    let mut i = 0;
+    while i < bits.len() {
+        let run = bits[i..].iter().take_while(|&&b| b == bits[i]).count();
+        let c = char::from(0x3f + bits[i]);
+        if run > 3 {
+            out.push_str(&format!("!{run}{c}"));
+        } else {
+            out.extend(std::iter::repeat_n(c, run));
+        }
+        i += run;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+
+    use crate::preview::{self, ImagePreview};
+
+    fn png(image: &RgbaImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        png
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(preview::detect("xterm-kitty", "", false), ImagePreview::Kitty);
+        assert_eq!(preview::detect("xterm-256color", "", true), ImagePreview::Kitty);
+        assert_eq!(preview::detect("xterm-256color", "iTerm.app", false), ImagePreview::Iterm);
+        assert_eq!(preview::detect("foot", "", false), ImagePreview::Sixel);
+        assert_eq!(preview::detect("xterm-256color", "", false), ImagePreview::Blocks);
+        assert_eq!(ImagePreview::Sixel.resolve(), ImagePreview::Sixel);
+    }
+
+    #[test]
+    fn test_blocks() {
+        // red on top of blue, and a fully transparent column
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+        image.put_pixel(0, 1, Rgba([0, 0, 255, 255]));
+        let rows = preview::blocks(&DynamicImage::ImageRgba8(image), 40);
+        assert_eq!(
+            rows,
+            ["\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m▀\x1b[38;2;0;0;0m\x1b[48;2;0;0;0m▀\x1b[0m"]
+        );
+
+        // scaled down to fit, keeping the aspect ratio
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(100, 50));
+        let rows = preview::half_blocks(&image, 20);
+        assert_eq!(rows.len(), 5);
+        assert_eq!(rows[0].len(), 20);
+    }
+
+    #[test]
+    fn test_protocols() -> Result<(), image::ImageError> {
+        let data = png(&RgbaImage::from_pixel(12, 12, Rgba([0, 255, 0, 255])));
+
+        let sixel = preview::render(&data, ImagePreview::Sixel, 4)?;
+        assert!(sixel.starts_with("\x1bPq\"1;1;12;12"));
+        assert!(sixel.ends_with("\x1b\\\n"));
+        // two full bands of green, the colour cube's 0;5;0
+        assert_eq!(sixel.matches("#30!12~$-").count(), 2);
+
+        let kitty = preview::render(&data, ImagePreview::Kitty, 4)?;
+        assert!(kitty.starts_with("\x1b_Ga=T,f=100,c=4,m=0;"));
+        let iterm = preview::render(&data, ImagePreview::Iterm, 4)?;
+        assert!(iterm.starts_with(&format!("\x1b]1337;File=inline=1;size={};width=4;", data.len())));
+
+        assert_eq!(preview::render(&data, ImagePreview::Off, 4)?, "");
+        assert!(preview::render(b"not an image", ImagePreview::Blocks, 4).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_kitty_chunks() {
+        let data = vec![0u8; 10_000];
+        let kitty = preview::kitty(&data, 4);
+        assert_eq!(kitty.matches("\x1b_G").count(), 4);
+        assert_eq!(kitty.matches("m=1;").count(), 3);
+        assert!(kitty.contains("\x1b_Gm=0;"));
+    }
+}
@@ -0,0 +1,92 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use rust_chat::commands::ClientCommand;
+
+/// A line of input in `--json` mode, e.g. `{"command": "text", "text": "hello"}`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "command", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ScriptCommand {
+    Text {
+        text: String,
+        #[serde(default)]
+        reply_to: Option<i64>,
+    },
+    File {
+        path: PathBuf,
+    },
+    Image {
+        path: PathBuf,
+    },
+    Edit {
+        id: i64,
+        text: String,
+    },
+    Delete {
+        id: i64,
+    },
+    User {
+        #[serde(default)]
+        name: Option<String>,
+    },
+    Stop,
+}
+
+impl ScriptCommand {
+    pub fn parse(line: &str) -> Result<ClientCommand, serde_json::Error> {
+        Ok(serde_json::from_str::<ScriptCommand>(line)?.into())
+    }
+}
+
+impl From<ScriptCommand> for ClientCommand {
+    fn from(command: ScriptCommand) -> Self {
+        match command {
+            ScriptCommand::Text { text, reply_to: Some(reply_to) } => ClientCommand::Reply { reply_to, text },
+            ScriptCommand::Text { text, reply_to: None } => ClientCommand::Text(text),
+            ScriptCommand::File { path } => ClientCommand::File { path },
+            ScriptCommand::Image { path } => ClientCommand::Image { path },
+            ScriptCommand::Edit { id, text } => ClientCommand::Edit { id, new_text: text },
+            ScriptCommand::Delete { id } => ClientCommand::Delete { id },
+            ScriptCommand::User { name } => ClientCommand::SetUser { username: name },
+            ScriptCommand::Stop => ClientCommand::Stop,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::path::PathBuf;
+
+    use rust_chat::commands::ClientCommand;
+
+    use crate::script::ScriptCommand;
+
+    #[test]
+    fn test_parse() -> Result<(), Box<dyn Error>> {
+        assert_eq!(
+            ScriptCommand::parse(r#"{"command": "text", "text": ".stop"}"#)?,
+            ClientCommand::Text(String::from(".stop"))
+        );
+        assert_eq!(
+            ScriptCommand::parse(r#"{"command": "text", "text": "yes", "reply_to": 3}"#)?,
+            ClientCommand::Reply { reply_to: 3, text: String::from("yes") }
+        );
+        assert_eq!(
+            ScriptCommand::parse(r#"{"command": "file", "path": "build.log"}"#)?,
+            ClientCommand::File { path: PathBuf::from("build.log") }
+        );
+        assert_eq!(
+            ScriptCommand::parse(r#"{"command": "user", "name": "ci"}"#)?,
+            ClientCommand::SetUser { username: Some(String::from("ci")) }
+        );
+        assert_eq!(ScriptCommand::parse(r#"{"command": "user"}"#)?, ClientCommand::SetUser { username: None });
+        assert_eq!(ScriptCommand::parse(r#"{"command": "stop"}"#)?, ClientCommand::Stop);
+
+        assert!(ScriptCommand::parse("hello").is_err());
+        assert!(ScriptCommand::parse(r#"{"command": "help"}"#).is_err());
+        assert!(ScriptCommand::parse(r#"{"command": "delete", "id": 1, "extra": true}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,40 @@
+use std::io::{self, Stdout};
+use std::panic;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crossterm::cursor::Show;
+use crossterm::execute;
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::Terminal;
+
+/// Whether `enter` has set up the terminal, so `restore` only undoes it once.
+static ENTERED: AtomicBool = AtomicBool::new(false);
+
+/// Switch the terminal to raw mode on the alternate screen, so keys are read as they're pressed, returning it to draw on.
+///
+/// The terminal is restored by `restore`, which also runs if the client panics.
+pub fn enter() -> io::Result<Terminal<CrosstermBackend<Stdout>>> {
+    enable_raw_mode()?;
+    ENTERED.store(true, Ordering::SeqCst);
+
+    let hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore();
+        hook(info);
+    }));
+
+    execute!(io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stdout()))?;
+    terminal.clear()?;
+    Ok(terminal)
+}
+
+/// Leave the alternate screen and restore the terminal settings, if `enter` changed them.
+pub fn restore() {
+    if ENTERED.swap(false, Ordering::SeqCst) {
+        // nothing more can be done if these fail, the client is exiting
+        let _ = disable_raw_mode();
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+    }
+}
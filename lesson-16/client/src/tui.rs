@@ -0,0 +1,454 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+use std::io::{self, Stdout};
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use ratatui::{Frame, Terminal};
+use tokio::select;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+use rust_chat::commands;
+
+use crate::preview::HalfBlock;
+use crate::ui::UiEvent;
+
+/// Width of the user list, including its border.
+const SIDEBAR_WIDTH: u16 = 20;
+/// Narrower terminals don't show the user list, to leave room for messages.
+const SIDEBAR_MIN_TERMINAL_WIDTH: u16 = 60;
+/// Number of messages kept for scrolling back.
+const SCROLLBACK: usize = 5000;
+const TOO_WIDE: &str = "[image, widen the terminal to preview]";
+const HELP: &str = "PgUp/PgDn scroll, Up/Down history, Tab complete, .help commands, Ctrl-C quit";
+const PROMPT: &str = "> ";
+
+/// State of the full screen interface.
+#[derive(Default)]
+pub struct App {
+    messages: Vec<Entry>,
+    /// Lines scrolled up from the newest message.
+    scroll: usize,
+    /// Width of the message pane when it was last drawn, to know how many lines new messages wrap to.
+    pane_width: Cell<usize>,
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Position in `history` while browsing it with up and down, and the line being typed before that.
+    browsing: Option<(usize, Vec<char>)>,
+    users: BTreeSet<String>,
+    /// Shown in the status bar until the next key press.
+    notice: Option<String>,
+}
+
+/// An entry in the message pane.
+enum Entry {
+    /// Plain text, wrapped to the width of the pane.
+    Text(String),
+    /// Rows of half blocks, which can't be wrapped, all `width` cells wide.
+    Image { rows: Vec<Vec<HalfBlock>>, width: usize },
+}
+
+impl Entry {
+    /// Number of lines the entry takes up in a pane `width` cells wide.
+    fn height(&self, width: usize) -> usize {
+        match self {
+            Entry::Image { rows, width: image_width } if *image_width <= width => rows.len(),
+            Entry::Image { .. } => wrap(TOO_WIDE, width).len(),
+            Entry::Text(message) => wrap(message, width).len(),
+        }
+    }
+}
+
+/// What the event loop should do after a key press.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Action {
+    None,
+    Submit(String),
+    Quit,
+}
+
+impl App {
+    pub fn handle_event(&mut self, event: UiEvent) {
+        match event {
+            UiEvent::Message(line) => self.push_message(Entry::Text(line)),
+            UiEvent::Notice(text) => {
+                self.push_message(Entry::Text(format!("* {text}")));
+                self.notice = Some(text);
+            }
+            UiEvent::Image { rows, width } => self.push_message(Entry::Image { rows, width }),
+            UiEvent::User(username) => {
+                self.users.insert(username);
+            }
+        }
+    }
+
+    fn push_message(&mut self, entry: Entry) {
+        // keep the view still while scrolled back
+        if self.scroll > 0 {
+            self.scroll += entry.height(self.pane_width.get());
+        }
+        self.messages.push(entry);
+        if self.messages.len() > SCROLLBACK {
+            self.messages.remove(0);
+        }
+    }
+
+    pub fn handle_key(&mut self, key: KeyEvent, page_height: usize) -> Action {
+        self.notice = None;
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c' | 'd') if control => return Action::Quit,
+            KeyCode::Char('u') if control => {
+                self.input.clear();
+                self.cursor = 0;
+            }
+            KeyCode::Char(c) if !control && !key.modifiers.contains(KeyModifiers::ALT) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Tab => self.complete(),
+            KeyCode::Up => self.browse_history(true),
+            KeyCode::Down => self.browse_history(false),
+            KeyCode::PageUp => self.scroll += page_height.max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(page_height.max(1)),
+            KeyCode::Enter => {
+                let line: String = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                self.scroll = 0;
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                return Action::Submit(line);
+            }
+            _ => {}
+        }
+        Action::None
+    }
+
+    /// Complete the command name or path being typed, listing the options in the status bar if there are several.
+    fn complete(&mut self) {
+        if self.cursor != self.input.len() {
+            return;
+        }
+        let line: String = self.input.iter().collect();
+        let candidates = commands::complete(&line);
+        let completed = commands::common_prefix(&candidates);
+        if completed.len() > line.len() {
+            self.input = completed.chars().collect();
+            self.cursor = self.input.len();
+        }
+        if candidates.len() > 1 {
+            // only show the part that differs, not the command and directory each time
+            let start = completed.rfind([' ', '/']).map_or(0, |i| i + 1);
+            let options: Vec<&str> = candidates.iter().map(|candidate| &candidate[start..]).collect();
+            self.notice = Some(options.join("  "));
+        }
+    }
+
+    fn browse_history(&mut self, older: bool) {
+        let index = match (&self.browsing, older) {
+            (None, true) if !self.history.is_empty() => {
+                self.browsing = Some((self.history.len(), self.input.clone()));
+                self.history.len() - 1
+            }
+            (Some((index, _)), true) => index.saturating_sub(1),
+            (Some((index, _)), false) if index + 1 < self.history.len() => index + 1,
+            (Some(_), false) => {
+                // past the newest entry, back to what was being typed
+                if let Some((_, draft)) = self.browsing.take() {
+                    self.input = draft;
+                    self.cursor = self.input.len();
+                }
+                return;
+            }
+            _ => return,
+        };
+        if let Some((current, _)) = &mut self.browsing {
+            *current = index;
+        }
+        self.input = self.history[index].chars().collect();
+        self.cursor = self.input.len();
+    }
+
+    /// Draw the whole screen: messages, the user list if there's room, then the status bar and input line.
+    pub fn draw(&self, frame: &mut Frame) {
+        let [main, status, input] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1), Constraint::Length(1)]).areas(frame.area());
+        let pane = if main.width >= SIDEBAR_MIN_TERMINAL_WIDTH {
+            let [pane, sidebar] =
+                Layout::horizontal([Constraint::Min(0), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+            self.draw_users(frame, sidebar);
+            pane
+        } else {
+            main
+        };
+        self.draw_messages(frame, pane);
+
+        let status_text = match (&self.notice, self.scroll) {
+            (Some(notice), _) => notice.clone(),
+            (None, 0) => HELP.to_string(),
+            (None, _) => format!("Scrolled back, PgDn to return | {HELP}"),
+        };
+        let reversed = Style::new().add_modifier(Modifier::REVERSED);
+        frame.render_widget(Paragraph::new(status_text).style(reversed), status);
+
+        // scroll the input sideways to keep the cursor on screen
+        let input_width = (input.width as usize).saturating_sub(PROMPT.len() + 1).max(1);
+        let start = self.cursor.saturating_sub(input_width);
+        let visible: String = self.input[start..].iter().take(input_width).collect();
+        frame.render_widget(Paragraph::new(format!("{PROMPT}{visible}")), input);
+        let column = (PROMPT.len() + self.cursor - start) as u16;
+        frame.set_cursor_position((input.x + column.min(input.width.saturating_sub(1)), input.y));
+    }
+
+    /// Draw the newest messages that fit in `area`, or older ones while scrolled back.
+    fn draw_messages(&self, frame: &mut Frame, area: Rect) {
+        let width = area.width as usize;
+        let height = area.height as usize;
+        self.pane_width.set(width);
+        let lines: Vec<Line> = self
+            .messages
+            .iter()
+            .flat_map(|entry| -> Vec<Line> {
+                match entry {
+                    Entry::Image { rows, width: image_width } if *image_width <= width => {
+                        rows.iter().map(|row| image_line(row)).collect()
+                    }
+                    Entry::Image { .. } => wrap(TOO_WIDE, width).into_iter().map(Line::raw).collect(),
+                    Entry::Text(message) => wrap(message, width).into_iter().map(Line::raw).collect(),
+                }
+            })
+            .collect();
+        let end = lines.len().saturating_sub(self.scroll.min(lines.len().saturating_sub(height)));
+        let visible = lines[end.saturating_sub(height)..end].to_vec();
+        frame.render_widget(Paragraph::new(visible), area);
+    }
+
+    fn draw_users(&self, frame: &mut Frame, area: Rect) {
+        let title = Line::styled(
+            format!(" Users ({})", self.users.len()),
+            Style::new().add_modifier(Modifier::BOLD),
+        );
+        let users = self.users.iter().map(|user| Line::raw(format!(" {user}")));
+        let list = Paragraph::new(std::iter::once(title).chain(users).collect::<Vec<_>>())
+            .block(Block::new().borders(Borders::LEFT));
+        frame.render_widget(list, area);
+    }
+}
+
+/// A row of an image preview, each cell a `▀` coloured with its top pixel, on its bottom pixel.
+fn image_line(row: &[HalfBlock]) -> Line<'static> {
+    row.iter()
+        .map(|&([tr, tg, tb], [br, bg, bb])| {
+            Span::styled("▀", Style::new().fg(Color::Rgb(tr, tg, tb)).bg(Color::Rgb(br, bg, bb)))
+        })
+        .collect()
+}
+
+/// Split `line` into rows of at most `width` characters.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width.max(1)).map(|chunk| chunk.iter().collect()).collect()
+}
+
+/// Run the interface on `terminal` until the client exits, sending each submitted line to `input`.
+pub async fn run(
+    mut terminal: Terminal<CrosstermBackend<Stdout>>,
+    mut events: mpsc::UnboundedReceiver<UiEvent>,
+    input: mpsc::Sender<String>,
+) -> io::Result<()> {
+    let mut terminal_events = EventStream::new();
+    let mut app = App::default();
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        select! {
+            Some(event) = terminal_events.next() => {
+                // resizes only need the redraw at the top of the loop, ratatui picks up the new size itself
+                let Event::Key(key) = event? else {
+                    continue;
+                };
+                // some terminals also report key releases
+                if key.kind == KeyEventKind::Release {
+                    continue;
+                }
+                let page_height = terminal.size()?.height.saturating_sub(2) as usize;
+                let line = match app.handle_key(key, page_height) {
+                    Action::None => continue,
+                    Action::Submit(line) => line,
+                    // handled like any other command, so the client exits the same way in both modes
+                    Action::Quit => String::from(".stop"),
+                };
+                if input.send(line).await.is_err() {
+                    return Ok(());
+                }
+            }
+            Some(event) = events.recv() => app.handle_event(event),
+            else => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use ratatui::backend::TestBackend;
+    use ratatui::style::Color;
+    use ratatui::Terminal;
+
+    use crate::tui::{self, Action, App};
+    use crate::ui::UiEvent;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn ctrl(c: char) -> KeyEvent {
+        KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL)
+    }
+
+    fn type_line(app: &mut App, line: &str) -> Action {
+        for c in line.chars() {
+            app.handle_key(key(KeyCode::Char(c)), 10);
+        }
+        app.handle_key(key(KeyCode::Enter), 10)
+    }
+
+    /// Draw `app` on a `width` by `height` terminal, returning its rows.
+    fn draw(app: &App, width: u16, height: u16) -> Terminal<TestBackend> {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        terminal
+    }
+
+    fn screen(terminal: &Terminal<TestBackend>) -> String {
+        let buffer = terminal.backend().buffer();
+        let mut screen = String::new();
+        for y in 0..buffer.area.height {
+            for x in 0..buffer.area.width {
+                screen.push_str(buffer[(x, y)].symbol());
+            }
+            screen.push('\n');
+        }
+        screen
+    }
+
+    #[test]
+    fn test_input_history() {
+        let mut app = App::default();
+        assert_eq!(type_line(&mut app, "first"), Action::Submit(String::from("first")));
+        assert_eq!(type_line(&mut app, "second"), Action::Submit(String::from("second")));
+
+        app.handle_key(key(KeyCode::Char('x')), 10);
+        app.handle_key(key(KeyCode::Up), 10);
+        app.handle_key(key(KeyCode::Up), 10);
+        assert_eq!(app.input.iter().collect::<String>(), "first");
+        app.handle_key(key(KeyCode::Down), 10);
+        app.handle_key(key(KeyCode::Down), 10);
+        // back to the unsent draft
+        assert_eq!(app.input.iter().collect::<String>(), "x");
+        app.handle_key(key(KeyCode::Left), 10);
+        app.handle_key(key(KeyCode::Char('y')), 10);
+        assert_eq!(app.handle_key(key(KeyCode::Enter), 10), Action::Submit(String::from("yx")));
+        assert_eq!(app.handle_key(ctrl('c'), 10), Action::Quit);
+        assert_eq!(app.handle_key(ctrl('d'), 10), Action::Quit);
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut app = App::default();
+        for c in ".de".chars() {
+            app.handle_key(key(KeyCode::Char(c)), 10);
+        }
+        app.handle_key(key(KeyCode::Tab), 10);
+        assert_eq!(app.input.iter().collect::<String>(), ".delete ");
+
+        app.handle_key(ctrl('u'), 10);
+        app.handle_key(key(KeyCode::Char('.')), 10);
+        app.handle_key(key(KeyCode::Tab), 10);
+        assert!(app.notice.as_deref().is_some_and(|notice| notice.contains(".help") && notice.contains(".stop")));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut app = App::default();
+        app.handle_event(UiEvent::User(String::from("alice")));
+        for i in 0..30 {
+            app.handle_event(UiEvent::Message(format!("message {i}")));
+        }
+        app.handle_event(UiEvent::Notice(String::from("alice sent a file")));
+        for c in "hi".chars() {
+            app.handle_key(key(KeyCode::Char(c)), 8);
+        }
+
+        let mut terminal = draw(&app, 80, 10);
+        let screen = screen(&terminal);
+        assert!(screen.contains("* alice sent a file"));
+        assert!(screen.contains("message 29"));
+        assert!(!screen.contains("message 22"));
+        assert!(screen.contains(" Users (1)"));
+        assert!(screen.contains(" alice"));
+        assert!(screen.contains("> hi"));
+        assert_eq!(terminal.get_cursor_position().unwrap(), (4, 9).into());
+
+        app.handle_key(key(KeyCode::PageUp), 8);
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        let screen = self::screen(&terminal);
+        assert!(screen.contains("message 22"));
+        assert!(!screen.contains("message 29"));
+        assert!(screen.contains("Scrolled back"));
+        // a message wrapped over several lines doesn't move the view either
+        app.handle_event(UiEvent::Message("x".repeat(150)));
+        terminal.draw(|frame| app.draw(frame)).unwrap();
+        assert_eq!(self::screen(&terminal), screen);
+
+        // too narrow for the user list
+        let screen = self::screen(&draw(&app, 40, 10));
+        assert!(!screen.contains("Users"));
+    }
+
+    #[test]
+    fn test_draw_image() {
+        let mut app = App::default();
+        let rows = vec![vec![([1, 2, 3], [4, 5, 6]); 50]; 2];
+        app.handle_event(UiEvent::Image { rows, width: 50 });
+
+        let terminal = draw(&app, 80, 10);
+        let cell = &terminal.backend().buffer()[(0, 1)];
+        assert_eq!(cell.symbol(), "▀");
+        assert_eq!((cell.fg, cell.bg), (Color::Rgb(1, 2, 3), Color::Rgb(4, 5, 6)));
+        assert_eq!(screen(&terminal).matches('▀').count(), 100);
+
+        // narrower than the image, which can't be wrapped
+        let screen = screen(&draw(&app, 40, 10));
+        assert!(!screen.contains('▀'));
+        assert!(screen.contains("[image, widen the"));
+    }
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(tui::wrap("abcdefg", 3), ["abc", "def", "g"]);
+        assert_eq!(tui::wrap("", 3), [""]);
+    }
+}
@@ -0,0 +1,271 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::exit;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tracing::{event, Level};
+
+use rust_chat::{otlp, Message, UserMessage};
+
+use crate::preview::{self, HalfBlock, ImagePreview};
+use crate::{terminal, tui};
+
+/// Output for the full screen interface, see `Ui`.
+pub enum UiEvent {
+    /// A line in the message pane.
+    Message(String),
+    /// Something worth pointing out, like a received file, shown in the message pane and the status bar.
+    Notice(String),
+    /// A user who has sent a message, for the user list.
+    User(String),
+    /// A preview of a received image, rows of half blocks from `preview::half_blocks`, all `width` cells wide.
+    Image { rows: Vec<Vec<HalfBlock>>, width: usize },
+}
+
+/// A received message as printed in `--json` mode, the same as the `UserMessage` except for files and photos.
+#[derive(Serialize)]
+struct JsonMessage<'a> {
+    id: Option<i64>,
+    timestamp: Option<DateTime<Utc>>,
+    username: Option<&'a str>,
+    message: JsonBody<'a>,
+    reply_to: Option<i64>,
+}
+
+/// Files and photos are saved like in the other modes, scripts get the path rather than the bytes, which would be
+/// printed as an array of numbers. `path` is `None` if saving failed.
+#[derive(Serialize)]
+enum JsonBody<'a> {
+    File { name: &'a str, mime: &'a str, path: Option<&'a Path> },
+    Photo { mime: &'static str, path: Option<&'a Path> },
+    #[serde(untagged)]
+    Other(&'a Message),
+}
+
+impl<'a> JsonMessage<'a> {
+    fn new(message: &'a UserMessage, saved: Option<&'a Path>) -> Self {
+        let body = match &message.message {
+            Message::File { name, mime, .. } => JsonBody::File { name, mime, path: saved },
+            Message::Photo { .. } => JsonBody::Photo { mime: "image/png", path: saved },
+            other => JsonBody::Other(other),
+        };
+        JsonMessage {
+            id: message.id,
+            timestamp: message.timestamp,
+            username: message.username.as_deref(),
+            message: body,
+            reply_to: message.reply_to,
+        }
+    }
+}
+
+/// Where the client shows messages, either printed line by line, or in the full screen interface.
+#[derive(Clone)]
+pub enum Ui {
+    Plain,
+    Tui(mpsc::UnboundedSender<UiEvent>),
+    /// For scripts, received messages are printed as JSON, and anything meant for people goes to stderr.
+    Json,
+}
+
+impl Ui {
+    /// Start the interface, returning it and the lines entered by the user.
+    pub fn start(plain: bool) -> io::Result<(Ui, mpsc::Receiver<String>)> {
+        let (input_tx, input_rx) = mpsc::channel(64);
+        if plain {
+            tokio::spawn(read_lines(input_tx));
+            return Ok((Ui::Plain, input_rx));
+        }
+
+        let terminal = terminal::enter()?;
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = tui::run(terminal, events_rx, input_tx).await {
+                terminal::restore();
+                event!(Level::ERROR, "Terminal interface failed: {e}");
+                exit(1);
+            }
+        });
+        Ok((Ui::Tui(events_tx), input_rx))
+    }
+
+    /// Start reading JSON commands from stdin, see `Ui::Json`.
+    pub fn start_json() -> (Ui, mpsc::Receiver<String>) {
+        let (input_tx, input_rx) = mpsc::channel(64);
+        tokio::spawn(read_lines(input_tx));
+        (Ui::Json, input_rx)
+    }
+
+    /// Print a received message as a line of JSON, in `--json` mode, with the path a file or photo was `saved` to.
+    pub fn json(&self, message: &UserMessage, saved: Option<&Path>) {
+        // only fails for maps with non-string keys
+        let line = serde_json::to_string(&JsonMessage::new(message, saved)).expect("Failed to serialize message...");
+        let mut stdout = io::stdout().lock();
+        // a closed stdout means nothing is listening anymore, the same as closing stdin
+        if writeln!(stdout, "{line}").and_then(|_| stdout.flush()).is_err() {
+            self.exit(0);
+        }
+    }
+
+    pub fn message(&self, line: impl Into<String>) {
+        self.send(UiEvent::Message(strip_controls(&line.into())));
+    }
+
+    pub fn notice(&self, text: impl Into<String>) {
+        self.send(UiEvent::Notice(strip_controls(&text.into())));
+    }
+
+    pub fn user(&self, username: &str) {
+        if let Ui::Tui(_) = self {
+            self.send(UiEvent::User(strip_controls(username)));
+        }
+    }
+
+    /// Preview a received image, encoded as PNG, at most `columns` cells wide.
+    ///
+    /// The full screen interface redraws over anything it didn't draw itself, so always uses half blocks.
+    pub fn image(&self, png: &[u8], preview: ImagePreview, columns: u32) -> Result<(), image::ImageError> {
+        match self {
+            Ui::Plain => {
+                print!("{}", preview::render(png, preview, columns)?);
+                io::stdout().flush()?;
+            }
+            Ui::Json => {}
+            Ui::Tui(_) => {
+                let rows = preview::half_blocks(&image::load_from_memory(png)?, columns);
+                let width = rows.first().map_or(0, Vec::len);
+                self.send(UiEvent::Image { rows, width });
+            }
+        }
+        Ok(())
+    }
+
+    fn send(&self, event: UiEvent) {
+        match self {
+            Ui::Plain => match event {
+                UiEvent::Message(line) | UiEvent::Notice(line) => println!("{line}"),
+                // images are printed by `image`, with whichever protocol the terminal supports
+                UiEvent::User(_) | UiEvent::Image { .. } => {}
+            },
+            Ui::Json => match event {
+                UiEvent::Message(line) | UiEvent::Notice(line) => eprintln!("{line}"),
+                UiEvent::User(_) | UiEvent::Image { .. } => {}
+            },
+            // only fails once the interface has stopped, when the client is exiting anyway
+            Ui::Tui(events) => {
+                let _ = events.send(event);
+            }
+        }
+    }
+
+    /// Restore the terminal and exit.
+    pub fn exit(&self, code: i32) -> ! {
+        terminal::restore();
+        flush_spans();
+        exit(code);
+    }
+
+    /// Restore the terminal and exit with `code`, printing `error` once it's back to normal.
+    pub fn exit_with_error(&self, code: i32, error: &str) -> ! {
+        terminal::restore();
+        eprintln!("{error}");
+        flush_spans();
+        exit(code);
+    }
+}
+
+/// Remove control characters, which other users could send to move the cursor, change colours or clear the screen
+/// when their messages are drawn. Line breaks and tabs become spaces, so each message stays on its own line.
+fn strip_controls(text: &str) -> String {
+    text.chars()
+        .filter_map(|c| match c {
+            '\n' | '\r' | '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+/// Export the spans still waiting, `exit` doesn't run destructors.
+fn flush_spans() {
+    if let Err(e) = otlp::shutdown() {
+        event!(Level::WARN, "Failed to export remaining spans: {e}");
+    }
+}
+
+/// Send each line of stdin to `input`, until stdin is closed.
+async fn read_lines(input: mpsc::Sender<String>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        match lines.next_line().await {
+            Ok(Some(line)) => {
+                if input.send(line.trim().to_string()).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => return,
+            Err(e) => {
+                event!(Level::ERROR, "Failed to read input: {e}");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use serde_json::json;
+
+    use rust_chat::{Message, UserMessage};
+
+    use crate::ui::{self, JsonMessage};
+
+    #[test]
+    fn test_strip_controls() {
+        assert_eq!(ui::strip_controls("hi\x1b[2J\x1b]0;pwned\x07 there"), "hi[2J]0;pwned there");
+        assert_eq!(ui::strip_controls("two\nlines\tand\r\x08"), "two lines and ");
+        assert_eq!(ui::strip_controls("héllo 👋"), "héllo 👋");
+    }
+
+    #[test]
+    fn test_json_message() {
+        let file = UserMessage {
+            id: Some(3),
+            ..UserMessage::new(
+                Some(String::from("alice")),
+                Message::File {
+                    name: String::from("notes.txt"),
+                    mime: String::from("text/plain"),
+                    data: vec![1, 2, 3],
+                },
+            )
+        };
+        let saved = Path::new("files/notes.txt");
+        assert_eq!(
+            serde_json::to_value(JsonMessage::new(&file, Some(saved))).unwrap(),
+            json!({
+                "id": 3,
+                "timestamp": null,
+                "username": "alice",
+                "message": {"File": {"name": "notes.txt", "mime": "text/plain", "path": "files/notes.txt"}},
+                "reply_to": null,
+            })
+        );
+
+        let photo = UserMessage::new(None, Message::Photo { data: vec![1, 2, 3] });
+        let value = serde_json::to_value(JsonMessage::new(&photo, None)).unwrap();
+        assert_eq!(value["message"], json!({"Photo": {"mime": "image/png", "path": null}}));
+
+        // everything else is printed as sent
+        let text = UserMessage::new(None, Message::Text(String::from("hi")));
+        assert_eq!(
+            serde_json::to_value(JsonMessage::new(&text, None)).unwrap(),
+            serde_json::to_value(&text).unwrap()
+        );
+    }
+}
@@ -0,0 +1,193 @@
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::Stream;
+
+use crate::{Message, UserMessage, MAX_MESSAGE_LEN, MAX_SEND_LEN};
+
+/// Messages read ahead of the `Messages` stream.
+const RECEIVE_BUFFER: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("Failed to connect to server: {0}")]
+    ConnectFailed(io::Error),
+    #[error("Lost connection to the server: {0}")]
+    ConnectionLost(#[from] io::Error),
+    #[error("Failed to serialize message: {0}")]
+    SerializeFailed(serde_cbor::Error),
+    #[error("Received an invalid message from the server: {0}")]
+    InvalidMessage(serde_cbor::Error),
+    #[error("Message of {0} bytes is too long to send.")]
+    MessageTooLong(usize),
+    #[error("The server sent a message of {0} bytes, more than the limit.")]
+    ReceivedTooLong(usize),
+}
+
+/// A connection to the chat server.
+///
+/// Sends messages with `send`, and is a `Stream` of the messages broadcast by the server, which ends once the server
+/// closes the connection. `split` gives separate halves, to send from one task while receiving in another.
+///
+/// # Examples
+/// ```no_run
+/// use rust_chat::client::ChatClient;
+/// use rust_chat::Message;
+/// use tokio_stream::StreamExt;
+///
+/// # async fn run() -> Result<(), rust_chat::client::ClientError> {
+/// let mut client = ChatClient::connect("127.0.0.1:11111").await?;
+/// client.set_username(Some(String::from("alice")));
+/// client.send(Message::Text(String::from("hello"))).await?;
+/// while let Some(message) = client.next().await {
+///     println!("{:?}", message?.message);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ChatClient {
+    sender: ChatSender,
+    messages: Messages,
+}
+
+impl ChatClient {
+    pub async fn connect(address: impl ToSocketAddrs) -> Result<ChatClient, ClientError> {
+        let stream = TcpStream::connect(address).await.map_err(ClientError::ConnectFailed)?;
+        let (reader, writer) = stream.into_split();
+        let (tx, rx) = mpsc::channel(RECEIVE_BUFFER);
+        tokio::spawn(receive(reader, tx));
+        Ok(ChatClient {
+            sender: ChatSender {
+                writer: Arc::new(Mutex::new(writer)),
+                username: None,
+            },
+            messages: Messages { receiver: rx },
+        })
+    }
+
+    /// The name messages are sent with, `None` to send them anonymously.
+    pub fn set_username(&mut self, username: Option<String>) {
+        self.sender.set_username(username);
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.sender.username()
+    }
+
+    /// Send `message` from the current username.
+    pub async fn send(&self, message: Message) -> Result<(), ClientError> {
+        self.sender.send(message).await
+    }
+
+    /// Send a message as is, for messages with more than just a `Message`, like replies.
+    pub async fn send_message(&self, message: &UserMessage) -> Result<(), ClientError> {
+        self.sender.send_message(message).await
+    }
+
+    pub fn split(self) -> (ChatSender, Messages) {
+        (self.sender, self.messages)
+    }
+}
+
+impl Stream for ChatClient {
+    type Item = Result<UserMessage, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.messages).poll_next(cx)
+    }
+}
+
+/// The sending half of a `ChatClient`, clones send over the same connection.
+#[derive(Clone)]
+pub struct ChatSender {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    username: Option<String>,
+}
+
+impl ChatSender {
+    pub fn set_username(&mut self, username: Option<String>) {
+        self.username = username;
+    }
+
+    pub fn username(&self) -> Option<&str> {
+        self.username.as_deref()
+    }
+
+    pub async fn send(&self, message: Message) -> Result<(), ClientError> {
+        self.send_message(&UserMessage::new(self.username.clone(), message)).await
+    }
+
+    pub async fn send_message(&self, message: &UserMessage) -> Result<(), ClientError> {
+        let serialized = serde_cbor::to_vec(message).map_err(ClientError::SerializeFailed)?;
+        if serialized.len() > MAX_SEND_LEN {
+            return Err(ClientError::MessageTooLong(serialized.len()));
+        }
+        // can't overflow, the limit is well under `u32::MAX`
+        let length = serialized.len() as u32;
+        // hold the lock for the whole message, so messages from clones aren't interleaved
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&length.to_le_bytes()).await?;
+        writer.write_all(&serialized).await?;
+        Ok(())
+    }
+}
+
+/// The receiving half of a `ChatClient`, a `Stream` of the messages broadcast by the server.
+///
+/// An error ends the stream, the connection can't be used after one.
+pub struct Messages {
+    receiver: mpsc::Receiver<Result<UserMessage, ClientError>>,
+}
+
+impl Stream for Messages {
+    type Item = Result<UserMessage, ClientError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// Read messages from the server until it closes the connection, or the `Messages` stream is dropped.
+async fn receive(mut reader: OwnedReadHalf, messages: mpsc::Sender<Result<UserMessage, ClientError>>) {
+    loop {
+        let message = match read_message(&mut reader).await {
+            Ok(Some(message)) => Ok(message),
+            // closed between messages, the stream just ends
+            Ok(None) => return,
+            Err(e) => Err(e),
+        };
+        let failed = message.is_err();
+        if messages.send(message).await.is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Read a message, sent as its length as a little endian `u32`, then the message as CBOR.
+///
+/// Messages over `MAX_MESSAGE_LEN` are rejected before anything is allocated for them.
+async fn read_message(reader: &mut OwnedReadHalf) -> Result<Option<UserMessage>, ClientError> {
+    let mut length = [0u8; 4];
+    let read = reader.read(&mut length).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[read..]).await?;
+
+    let length = u32::from_le_bytes(length) as usize;
+    if length > MAX_MESSAGE_LEN {
+        return Err(ClientError::ReceivedTooLong(length));
+    }
+    let mut message = vec![0u8; length];
+    reader.read_exact(&mut message).await?;
+    serde_cbor::from_slice(&message)
+        .map(Some)
+        .map_err(ClientError::InvalidMessage)
+}
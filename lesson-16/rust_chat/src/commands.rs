@@ -0,0 +1,582 @@
+use std::fs;
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::str::FromStr;
+
+use image::ImageFormat;
+
+use crate::{mime, Message, MessageError, UserMessage};
+
+/// How a command argument is parsed, and completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArgKind {
+    /// A message id, optionally prefixed with `#`.
+    Id,
+    /// A path to a local file, completed from the filesystem.
+    Path,
+    /// Free text.
+    Text,
+}
+
+#[derive(Debug)]
+pub struct ArgSpec {
+    pub name: &'static str,
+    pub kind: ArgKind,
+    pub optional: bool,
+}
+
+impl ArgSpec {
+    /// Ids are single words, anything else is the rest of the line, so it can contain spaces.
+    fn takes_rest(&self) -> bool {
+        self.kind != ArgKind::Id
+    }
+}
+
+/// A client command, such as `.file <path>`.
+#[derive(Debug)]
+pub struct CommandSpec {
+    /// The name, including the leading `.`.
+    pub name: &'static str,
+    pub args: &'static [ArgSpec],
+    pub description: &'static str,
+}
+
+const fn required(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: false,
+    }
+}
+
+const fn optional(name: &'static str, kind: ArgKind) -> ArgSpec {
+    ArgSpec {
+        name,
+        kind,
+        optional: true,
+    }
+}
+
+/// Every command the client understands, in the order `.help` lists them.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: ".help",
+        args: &[optional("command", ArgKind::Text)],
+        description: "List the commands, or describe one of them.",
+    },
+    CommandSpec {
+        name: ".user",
+        args: &[optional("name", ArgKind::Text)],
+        description: "Set your username, leave it blank to go anonymous.",
+    },
+    CommandSpec {
+        name: ".file",
+        args: &[required("path", ArgKind::Path)],
+        description: "Send a file.",
+    },
+    CommandSpec {
+        name: ".image",
+        args: &[required("path", ArgKind::Path)],
+        description: "Send an image, converted to .png.",
+    },
+    CommandSpec {
+        name: ".edit",
+        args: &[required("id", ArgKind::Id), required("text", ArgKind::Text)],
+        description: "Replace the text of one of your messages.",
+    },
+    CommandSpec {
+        name: ".delete",
+        args: &[required("id", ArgKind::Id)],
+        description: "Delete one of your messages.",
+    },
+    CommandSpec {
+        name: ".reply",
+        args: &[required("id", ArgKind::Id), required("text", ArgKind::Text)],
+        description: "Reply to a message.",
+    },
+    CommandSpec {
+        name: ".stop",
+        args: &[],
+        description: "Exit the client.",
+    },
+];
+
+/// A line of client input.
+///
+/// Parsing only checks the syntax, files are read when the command is turned into a message, by `into_message`.
+///
+/// # Examples
+/// ```
+/// use rust_chat::commands::ClientCommand;
+///
+/// let command: ClientCommand = ".delete #3".parse().unwrap();
+/// assert_eq!(command, ClientCommand::Delete { id: 3 });
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientCommand {
+    Text(String),
+    File { path: PathBuf },
+    Image { path: PathBuf },
+    Edit { id: i64, new_text: String },
+    Delete { id: i64 },
+    Reply { reply_to: i64, text: String },
+    SetUser { username: Option<String> },
+    Help { command: Option<String> },
+    Stop,
+}
+
+impl FromStr for ClientCommand {
+    type Err = MessageError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        if !is_command(line) {
+            return Ok(ClientCommand::Text(line.to_string()));
+        }
+        let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let command = find(name).ok_or_else(|| MessageError::UnknownCommand(name.to_string()))?;
+        let args = command.parse_args(rest)?;
+        // `parse_args` checked the required arguments are there
+        match command.name {
+            ".help" => Ok(ClientCommand::Help {
+                command: args.first().map(|name| name.to_string()),
+            }),
+            ".user" => Ok(ClientCommand::SetUser {
+                username: args.first().map(|name| name.to_string()),
+            }),
+            ".file" => Ok(ClientCommand::File {
+                path: PathBuf::from(args[0]),
+            }),
+            ".image" => Ok(ClientCommand::Image {
+                path: PathBuf::from(args[0]),
+            }),
+            ".edit" => Ok(ClientCommand::Edit {
+                id: parse_id(args[0])?,
+                new_text: args[1].to_string(),
+            }),
+            ".delete" => Ok(ClientCommand::Delete {
+                id: parse_id(args[0])?,
+            }),
+            ".reply" => Ok(ClientCommand::Reply {
+                reply_to: parse_id(args[0])?,
+                text: args[1].to_string(),
+            }),
+            ".stop" => Ok(ClientCommand::Stop),
+            // registered, but not handled above
+            _ => Err(MessageError::UnknownCommand(name.to_string())),
+        }
+    }
+}
+
+impl ClientCommand {
+    /// Turn the command into a message from `username`, reading the file it refers to, if any.
+    ///
+    /// Commands handled by the client itself, like `.user` or `.stop`, give `None`.
+    pub async fn into_message(
+        self,
+        username: Option<String>,
+    ) -> Result<Option<UserMessage>, MessageError> {
+        let (message, reply_to) = match self {
+            ClientCommand::Text(text) => (Message::Text(text), None),
+            ClientCommand::File { path } => (read_file(&path).await?, None),
+            ClientCommand::Image { path } => (read_image(path).await?, None),
+            ClientCommand::Edit { id, new_text } => (Message::Edit { id, new_text }, None),
+            ClientCommand::Delete { id } => (Message::Delete { id }, None),
+            ClientCommand::Reply { reply_to, text } => (Message::Text(text), Some(reply_to)),
+            ClientCommand::SetUser { .. } | ClientCommand::Help { .. } | ClientCommand::Stop => {
+                return Ok(None)
+            }
+        };
+        Ok(Some(UserMessage {
+            reply_to,
+            ..UserMessage::new(username, message)
+        }))
+    }
+}
+
+async fn check_file(path: &Path) -> Result<(), MessageError> {
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) if metadata.is_file() => Ok(()),
+        _ => Err(MessageError::FileNotFound(path.display().to_string())),
+    }
+}
+
+async fn read_file(path: &Path) -> Result<Message, MessageError> {
+    check_file(path).await?;
+    let data = tokio::fs::read(path).await?;
+    Ok(Message::File {
+        // a file always has a name
+        name: path.file_name().unwrap().to_string_lossy().to_string(),
+        mime: mime::detect(&data).to_string(),
+        data,
+    })
+}
+
+/// Read an image, converting it to PNG.
+async fn read_image(path: PathBuf) -> Result<Message, MessageError> {
+    check_file(&path).await?;
+    let data = tokio::fs::read(&path).await?;
+    // decoding is slow for large images, so keep it off the async threads
+    tokio::task::spawn_blocking(move || {
+        let image = image::load_from_memory(&data)?;
+        let mut png = Vec::new();
+        image.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        Ok(Message::Photo { data: png })
+    })
+    .await
+    .map_err(|e| MessageError::FileReadFailed(io::Error::other(e)))?
+}
+
+/// Parse a message id given as a command argument.
+fn parse_id(value: &str) -> Result<i64, MessageError> {
+    value
+        .trim_start_matches('#')
+        .parse()
+        .map_err(|_| MessageError::InvalidId(value.to_string()))
+}
+
+/// Whether `line` is a command rather than text, commands are a `.` followed by a letter.
+pub fn is_command(line: &str) -> bool {
+    let mut chars = line.chars();
+    chars.next() == Some('.') && chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+}
+
+/// Look up a command by name, with or without the leading `.`.
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    let name = name.trim_start_matches('.');
+    COMMANDS.iter().find(|command| &command.name[1..] == name)
+}
+
+impl CommandSpec {
+    /// The command with its arguments, e.g. `.edit <id> <text>`.
+    pub fn usage(&self) -> String {
+        let mut usage = self.name.to_string();
+        for arg in self.args {
+            if arg.optional {
+                usage.push_str(&format!(" [{}]", arg.name));
+            } else {
+                usage.push_str(&format!(" <{}>", arg.name));
+            }
+        }
+        usage
+    }
+
+    /// Split the text after the command name into its arguments, checking none are missing or left over.
+    pub fn parse_args<'a>(&self, rest: &'a str) -> Result<Vec<&'a str>, MessageError> {
+        let mut rest = rest.trim();
+        let mut values = Vec::new();
+        for arg in self.args {
+            let value = if arg.takes_rest() {
+                std::mem::take(&mut rest)
+            } else {
+                let (value, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+                rest = remaining.trim_start();
+                value
+            };
+            if value.is_empty() {
+                if arg.optional {
+                    break;
+                }
+                return Err(MessageError::Usage(self.usage()));
+            }
+            values.push(value);
+        }
+        if !rest.is_empty() {
+            return Err(MessageError::Usage(self.usage()));
+        }
+        Ok(values)
+    }
+}
+
+/// The `.help` listing, or the description of a single command.
+pub fn help(command: Option<&str>) -> Result<String, MessageError> {
+    if let Some(name) = command {
+        let command = find(name).ok_or_else(|| MessageError::UnknownCommand(name.to_string()))?;
+        return Ok(format!("{} - {}", command.usage(), command.description));
+    }
+    let width = COMMANDS
+        .iter()
+        .map(|command| command.usage().len())
+        .max()
+        .unwrap_or_default();
+    let lines: Vec<String> = COMMANDS
+        .iter()
+        .map(|command| format!("{:width$}  {}", command.usage(), command.description))
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// Complete the command name or path argument at the end of `line`, returning every possible completed line.
+///
+/// Relative paths are completed from the current directory.
+pub fn complete(line: &str) -> Vec<String> {
+    let Some((name, rest)) = line.split_once(' ') else {
+        if !line.starts_with('.') {
+            return Vec::new();
+        }
+        return COMMANDS
+            .iter()
+            .filter(|command| command.name.starts_with(line))
+            .map(|command| {
+                // commands without arguments are complete as they are
+                let space = if command.args.is_empty() { "" } else { " " };
+                format!("{}{space}", command.name)
+            })
+            .collect();
+    };
+    match find(name) {
+        Some(command) if command.args.first().is_some_and(|arg| arg.kind == ArgKind::Path) => {
+            complete_path(rest)
+                .into_iter()
+                .map(|path| format!("{name} {path}"))
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn complete_path(partial: &str) -> Vec<String> {
+    let (dir, prefix) = match partial.rfind(['/', MAIN_SEPARATOR]) {
+        Some(end) => (&partial[..=end], &partial[end + 1..]),
+        None => ("", partial),
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) else {
+        return Vec::new();
+    };
+    let mut completions: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // hidden files only when asked for
+            if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                return None;
+            }
+            let is_dir = entry.file_type().is_ok_and(|kind| kind.is_dir());
+            Some(format!("{dir}{name}{}", if is_dir { "/" } else { "" }))
+        })
+        .collect();
+    completions.sort();
+    completions
+}
+
+/// The longest prefix shared by all of `candidates`.
+pub fn common_prefix(candidates: &[String]) -> String {
+    let Some(first) = candidates.first() else {
+        return String::new();
+    };
+    let mut prefix: &str = first;
+    for candidate in &candidates[1..] {
+        let length = prefix
+            .char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map(|((i, _), _)| i)
+            .unwrap_or(prefix.len().min(candidate.len()));
+        prefix = &prefix[..length];
+    }
+    prefix.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+
+    use crate::commands::{self, ClientCommand, COMMANDS};
+    use crate::{Message, MessageError};
+
+    #[test]
+    fn test_parse_args() {
+        let edit = commands::find(".edit").unwrap();
+        assert_eq!(edit.parse_args(" 12  fixed the typo").unwrap(), ["12", "fixed the typo"]);
+        assert!(edit.parse_args("12").is_err());
+
+        let user = commands::find("user").unwrap();
+        assert_eq!(user.parse_args("").unwrap(), Vec::<&str>::new());
+        assert_eq!(user.parse_args("Jane Doe").unwrap(), ["Jane Doe"]);
+
+        let delete = commands::find(".delete").unwrap();
+        assert!(delete.parse_args("1 2").is_err());
+        assert!(commands::find(".stop").unwrap().parse_args("now").is_err());
+    }
+
+    #[test]
+    fn test_help() {
+        let help = commands::help(None).unwrap();
+        assert_eq!(help.lines().count(), COMMANDS.len());
+        assert!(help.contains(".edit <id> <text>"));
+        assert_eq!(
+            commands::help(Some("delete")).unwrap(),
+            ".delete <id> - Delete one of your messages."
+        );
+        assert!(commands::help(Some(".nope")).is_err());
+    }
+
+    #[test]
+    fn test_is_command() {
+        assert!(commands::is_command(".file"));
+        assert!(!commands::is_command("..."));
+        assert!(!commands::is_command(". hi"));
+        assert!(!commands::is_command("hello"));
+    }
+
+    #[test]
+    fn test_complete() -> Result<(), Box<dyn Error>> {
+        assert_eq!(commands::complete(".st"), [".stop"]);
+        assert_eq!(commands::complete(".e"), [".edit "]);
+        assert!(commands::complete("hello").is_empty());
+
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("notes.txt"), "")?;
+        fs::write(dir.path().join("notes.md"), "")?;
+        fs::create_dir(dir.path().join("nested"))?;
+        let base = format!("{}/", dir.path().display());
+        assert_eq!(
+            commands::complete(&format!(".file {base}no")),
+            [format!(".file {base}notes.md"), format!(".file {base}notes.txt")]
+        );
+        assert_eq!(commands::complete(&format!(".image {base}ne")), [format!(".image {base}nested/")]);
+        assert!(commands::complete(&format!(".edit {base}")).is_empty());
+
+        let candidates = commands::complete(&format!(".file {base}no"));
+        assert_eq!(commands::common_prefix(&candidates), format!(".file {base}notes."));
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from("hello");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::Text(value.clone());
+        assert_eq!(message, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".stop");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::Stop;
+        assert_eq!(message, expected);
+        Ok(())
+    }
+    #[test]
+    fn test_set_user_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".user Custom");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::SetUser {
+            username: Some(String::from("Custom")),
+        };
+        assert_eq!(message, expected);
+        Ok(())
+    }
+    #[test]
+    fn test_edit_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".edit #12 fixed typo");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::Edit {
+            id: 12,
+            new_text: String::from("fixed typo"),
+        };
+        assert_eq!(message, expected);
+        Ok(())
+    }
+    #[test]
+    fn test_edit_message_missing_text() {
+        let value = String::from(".edit 12");
+        assert!(value.parse::<ClientCommand>().is_err());
+    }
+    #[test]
+    fn test_reply_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".reply 7 agreed");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::Reply {
+            reply_to: 7,
+            text: String::from("agreed"),
+        };
+        assert_eq!(message, expected);
+        Ok(())
+    }
+    #[test]
+    fn test_delete_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".delete 3");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::Delete { id: 3 };
+        assert_eq!(message, expected);
+        Ok(())
+    }
+    #[test]
+    fn test_delete_message_invalid_id() {
+        let value = String::from(".delete abc");
+        assert!(value.parse::<ClientCommand>().is_err());
+    }
+    #[test]
+    fn test_unknown_command() {
+        let value = String::from(".shrug");
+        assert!(matches!(
+            value.parse::<ClientCommand>(),
+            Err(MessageError::UnknownCommand(name)) if name == ".shrug"
+        ));
+    }
+    #[test]
+    fn test_file_message_missing_path() {
+        let value = String::from(".file");
+        assert!(matches!(value.parse::<ClientCommand>(), Err(MessageError::Usage(_))));
+    }
+    #[test]
+    fn test_dots_are_text() -> Result<(), Box<dyn Error>> {
+        let value = String::from("...");
+        assert_eq!(value.parse::<ClientCommand>()?, ClientCommand::Text(value));
+        Ok(())
+    }
+    #[test]
+    fn test_set_user_empty_message() -> Result<(), Box<dyn Error>> {
+        let value = String::from(".user");
+        let message = value.parse::<ClientCommand>()?;
+        let expected = ClientCommand::SetUser { username: None };
+        assert_eq!(message, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_into_message() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("notes.txt");
+        fs::write(&path, "some notes")?;
+
+        let command: ClientCommand = format!(".file {}", path.display()).parse()?;
+        let message = command.into_message(Some(String::from("alice"))).await?.unwrap();
+        assert_eq!(
+            message.message,
+            Message::File {
+                name: String::from("notes.txt"),
+                mime: String::from("text/plain"),
+                data: b"some notes".to_vec()
+            }
+        );
+        assert_eq!(message.username.as_deref(), Some("alice"));
+
+        // not UTF-8, sent as is
+        let pdf = b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n\xff\x00".to_vec();
+        let path = dir.path().join("report.pdf");
+        fs::write(&path, &pdf)?;
+        let message = ClientCommand::File { path }.into_message(None).await?.unwrap();
+        assert_eq!(
+            message.message,
+            Message::File {
+                name: String::from("report.pdf"),
+                mime: String::from("application/pdf"),
+                data: pdf
+            }
+        );
+
+        let reply = ClientCommand::Reply { reply_to: 4, text: String::from("yes") };
+        let message = reply.into_message(None).await?.unwrap();
+        assert_eq!(message.message, Message::Text(String::from("yes")));
+        assert_eq!(message.reply_to, Some(4));
+
+        assert!(ClientCommand::Stop.into_message(None).await?.is_none());
+        let missing = ClientCommand::File { path: dir.path().join("missing.txt") };
+        assert!(matches!(missing.into_message(None).await, Err(MessageError::FileNotFound(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Parser};
+use thiserror::Error;
+use toml::Spanned;
+
+/// Checks on a loaded config that clap can't express, such as ranges or combinations of settings.
+pub trait Validate {
+    /// Return every problem with the config, so they can all be reported at once.
+    fn validate(&self) -> Vec<String>;
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("Failed to read config file {0}: {1}")]
+    Io(PathBuf, io::Error),
+    #[error("{0}:{1}: {2}")]
+    Syntax(PathBuf, usize, String),
+    #[error("Unknown setting \"{key}\" in {origin}")]
+    UnknownKey { key: String, origin: String },
+    #[error("Invalid value for \"{key}\" in {origin}: {message}")]
+    InvalidValue {
+        key: String,
+        origin: String,
+        message: String,
+    },
+    #[error("{}", .0.to_string().trim_end())]
+    Args(#[from] clap::Error),
+    #[error("Invalid config:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+impl ConfigError {
+    /// Print the error and exit, leaving `--help` and `--version` to clap.
+    pub fn exit(&self) -> ! {
+        if let ConfigError::Args(e) = self {
+            e.exit();
+        }
+        eprintln!("{self}");
+        exit(2);
+    }
+}
+
+/// Loads a config of type `P` from, in increasing priority, a TOML file, environment variables, and the command line.
+///
+/// Settings are named after the command line arguments, so `--db-path` is `db_path = "chat.db"` in the file,
+/// and `<PREFIX>DB_PATH` in the environment. The file is given by `--config`, or `<PREFIX>CONFIG`.
+/// Values from the file and environment are parsed by clap, exactly like arguments.
+pub struct Loader {
+    cli: Vec<OsString>,
+    vars: Vec<(String, String)>,
+    env_prefix: String,
+}
+
+impl Loader {
+    /// A loader for this process' arguments and environment.
+    pub fn new(env_prefix: &str) -> Self {
+        Loader::from_parts(env::args_os(), env::vars(), env_prefix)
+    }
+
+    pub fn from_parts(
+        cli: impl IntoIterator<Item = impl Into<OsString>>,
+        vars: impl IntoIterator<Item = (String, String)>,
+        env_prefix: &str,
+    ) -> Self {
+        Loader {
+            cli: cli.into_iter().map(Into::into).collect(),
+            vars: vars
+                .into_iter()
+                .filter(|(name, _)| name.starts_with(env_prefix))
+                .collect(),
+            env_prefix: env_prefix.to_string(),
+        }
+    }
+
+    /// The config file, if one was given on the command line or in the environment.
+    pub fn config_file<P: Parser>(&self) -> Result<Option<PathBuf>, ConfigError> {
+        let matches = P::command().try_get_matches_from(&self.cli)?;
+        Ok(self.config_file_from(&matches))
+    }
+
+    fn config_file_from(&self, matches: &ArgMatches) -> Option<PathBuf> {
+        if let Ok(Some(path)) = matches.try_get_one::<PathBuf>("config") {
+            return Some(path.clone());
+        }
+        self.var("CONFIG").map(PathBuf::from)
+    }
+
+    fn var(&self, key: &str) -> Option<&str> {
+        let name = format!("{}{key}", self.env_prefix);
+        self.vars
+            .iter()
+            .find(|(var, _)| *var == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Load and validate the config.
+    pub fn load<P: Parser + Validate>(&self) -> Result<P, ConfigError> {
+        let command = P::command();
+        let cli_matches = command.clone().try_get_matches_from(&self.cli)?;
+
+        // arguments that can be set from the file or environment, by id
+        let settable: BTreeMap<&str, &clap::Arg> = command
+            .get_arguments()
+            .filter(|arg| arg.get_long().is_some())
+            .map(|arg| (arg.get_id().as_str(), arg))
+            .filter(|(id, _)| !matches!(*id, "help" | "version" | "config"))
+            .collect();
+
+        let mut settings: Vec<(String, String, String)> = Vec::new();
+        if let Some(path) = self.config_file_from(&cli_matches) {
+            let text = fs::read_to_string(&path).map_err(|e| ConfigError::Io(path.clone(), e))?;
+            let origin = path.display().to_string();
+            for (key, value) in parse_toml(&text)
+                .map_err(|(line, message)| ConfigError::Syntax(path.clone(), line, message))?
+            {
+                settings.push((key, value, origin.clone()));
+            }
+        }
+        for (name, value) in &self.vars {
+            let key = name[self.env_prefix.len()..].to_lowercase();
+            if key != "config" {
+                settings.push((key, value.clone(), format!("${name}")));
+            }
+        }
+
+        // later layers override earlier ones, and the command line overrides both
+        let mut layered: BTreeMap<&str, (String, String)> = BTreeMap::new();
+        for (key, value, origin) in settings {
+            let Some((id, _)) = settable.get_key_value(key.as_str()) else {
+                return Err(ConfigError::UnknownKey { key, origin });
+            };
+            if cli_matches.value_source(id) != Some(ValueSource::CommandLine) {
+                layered.insert(id, (value, origin));
+            }
+        }
+
+        let mut argv: Vec<OsString> = self.cli.iter().take(1).cloned().collect();
+        for (id, (value, origin)) in layered {
+            let arg = settable[id];
+            // `settable` only holds arguments with a long name
+            let long = arg.get_long().unwrap_or_default();
+            let arg_values = if arg.get_action().takes_values() {
+                vec![OsString::from(format!("--{long}={value}"))]
+            } else {
+                // flags can't take a value on the command line, so only pass them when set
+                match value.as_str() {
+                    "true" => vec![OsString::from(format!("--{long}"))],
+                    "false" => vec![],
+                    _ => {
+                        return Err(ConfigError::InvalidValue {
+                            key: id.to_string(),
+                            origin,
+                            message: format!("expected true or false, got \"{value}\""),
+                        })
+                    }
+                }
+            };
+            // check each value on its own, so errors point at where it came from
+            let mut check = self.cli.iter().take(1).cloned().collect::<Vec<_>>();
+            check.extend(arg_values.iter().cloned());
+            if let Err(e) = command.clone().try_get_matches_from(check) {
+                return Err(ConfigError::InvalidValue {
+                    key: id.to_string(),
+                    origin,
+                    message: clap_message(&e),
+                });
+            }
+            argv.extend(arg_values);
+        }
+        argv.extend(self.cli.iter().skip(1).cloned());
+
+        let matches = command.try_get_matches_from(argv)?;
+        let config = P::from_arg_matches(&matches)?;
+        let problems = config.validate();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// Reload the config whenever the config file changes, checking every `interval`.
+    ///
+    /// `on_change` gets the new config, or the error if the file is now invalid, in which case the old config
+    /// should be kept. The command line and environment still override the file, as they did on startup.
+    pub fn watch<P, F>(self, interval: Duration, mut on_change: F) -> Result<(), ConfigError>
+    where
+        P: Parser + Validate,
+        F: FnMut(Result<P, ConfigError>) + Send + 'static,
+    {
+        let Some(path) = self.config_file::<P>()? else {
+            return Ok(());
+        };
+        let mut modified = modified_time(&path);
+        thread::Builder::new()
+            .name("config-watch".to_string())
+            .spawn(move || loop {
+                thread::sleep(interval);
+                let current = modified_time(&path);
+                if current != modified {
+                    modified = current;
+                    on_change(self.load());
+                }
+            })
+            .map_err(|e| ConfigError::Io(PathBuf::from("config-watch thread"), e))?;
+        Ok(())
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// The first line of a clap error, without its `error: ` prefix.
+fn clap_message(error: &clap::Error) -> String {
+    let rendered = error.to_string();
+    let line = rendered.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+/// Parse the top level `key = value` pairs of a TOML config file, which may be strings, integers, floats, booleans or
+/// dates. Keys may use `-` or `_`, `db-path` and `db_path` are the same setting.
+///
+/// Values are returned as they'd be written on the command line, errors as the line number and a message.
+pub fn parse_toml(text: &str) -> Result<Vec<(String, String)>, (usize, String)> {
+    let line_of = |offset: usize| text[..offset.min(text.len())].matches('\n').count() + 1;
+    let table: BTreeMap<String, Spanned<toml::Value>> = toml::from_str(text).map_err(|e: toml::de::Error| {
+        (e.span().map_or(1, |span| line_of(span.start)), e.message().to_string())
+    })?;
+
+    let mut values: Vec<(String, String)> = Vec::new();
+    for (key, value) in table {
+        let line = line_of(value.span().start);
+        let key = key.replace('-', "_");
+        if values.iter().any(|(existing, _)| *existing == key) {
+            return Err((line, format!("duplicate key \"{key}\"")));
+        }
+        let value = match value.into_inner() {
+            toml::Value::String(value) => value,
+            toml::Value::Integer(value) => value.to_string(),
+            toml::Value::Float(value) => value.to_string(),
+            toml::Value::Boolean(value) => value.to_string(),
+            toml::Value::Datetime(value) => value.to_string(),
+            toml::Value::Array(_) => return Err((line, format!("\"{key}\" can't be an array"))),
+            toml::Value::Table(_) => {
+                return Err((line, String::from("tables aren't supported, settings must be at the top level")))
+            }
+        };
+        values.push((key, value));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use clap::Parser;
+
+    use crate::config::{self, ConfigError, Loader, Validate};
+
+    #[derive(Parser, Debug)]
+    struct TestConfig {
+        #[arg(long)]
+        config: Option<PathBuf>,
+        #[arg(short, long, default_value_t = String::from("127.0.0.1"))]
+        address: String,
+        #[arg(short, long, default_value_t = 11111)]
+        port: u16,
+        #[arg(long, default_value_t = String::from("sqlite.db"))]
+        db_path: String,
+        #[arg(long)]
+        verbose: bool,
+    }
+
+    impl Validate for TestConfig {
+        fn validate(&self) -> Vec<String> {
+            let mut problems = Vec::new();
+            if self.port == 0 {
+                problems.push(String::from("port must not be 0"));
+            }
+            problems
+        }
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let parsed = config::parse_toml(
+            "# comment\naddress = \"0.0.0.0\" # trailing\nport = 8_080\nverbose = true\ndb-path = 'C:\\chat.db'\n",
+        );
+        assert_eq!(
+            parsed,
+            Ok(vec![
+                (String::from("address"), String::from("0.0.0.0")),
+                (String::from("db_path"), String::from("C:\\chat.db")),
+                (String::from("port"), String::from("8080")),
+                (String::from("verbose"), String::from("true")),
+            ])
+        );
+        assert_eq!(config::parse_toml("\n[server]\n").unwrap_err().0, 2);
+        assert!(config::parse_toml("address = 0.0.0.0").is_err());
+        assert!(config::parse_toml("address = \"open").is_err());
+        assert!(config::parse_toml("port = 1\nport = 2").is_err());
+        assert!(config::parse_toml("db_path = \"a\"\ndb-path = \"b\"").is_err());
+        assert_eq!(config::parse_toml("port = 1\nlevels = [1, 2]").unwrap_err().0, 2);
+    }
+
+    #[test]
+    fn test_layers() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.toml");
+        fs::write(&path, "address = \"0.0.0.0\"\nport = 2000\ndb_path = \"file.db\"\n")?;
+
+        let loader = Loader::from_parts(
+            ["chat", "--config", path.to_str().unwrap(), "--port", "4000"],
+            [
+                (String::from("CHAT_PORT"), String::from("3000")),
+                (String::from("CHAT_DB_PATH"), String::from("env.db")),
+                (String::from("CHAT_VERBOSE"), String::from("true")),
+                (String::from("OTHER_PORT"), String::from("5000")),
+            ],
+            "CHAT_",
+        );
+        let loaded: TestConfig = loader.load()?;
+        assert_eq!(loaded.address, "0.0.0.0");
+        assert_eq!(loaded.db_path, "env.db");
+        assert_eq!(loaded.port, 4000);
+        assert!(loaded.verbose);
+        Ok(())
+    }
+
+    #[test]
+    fn test_errors() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("chat.toml");
+        let load = |contents: &str| -> Result<TestConfig, ConfigError> {
+            fs::write(&path, contents).unwrap();
+            Loader::from_parts(["chat", "--config", path.to_str().unwrap()], [], "CHAT_").load()
+        };
+
+        assert!(matches!(load("prot = 1"), Err(ConfigError::UnknownKey { key, .. }) if key == "prot"));
+        assert!(matches!(load("port = \"high\""), Err(ConfigError::InvalidValue { key, .. }) if key == "port"));
+        assert!(matches!(load("verbose = 1"), Err(ConfigError::InvalidValue { key, .. }) if key == "verbose"));
+        assert!(matches!(load("port = 0"), Err(ConfigError::Invalid(problems)) if problems.len() == 1));
+        assert!(matches!(load("port = ="), Err(ConfigError::Syntax(_, 1, _))));
+        Ok(())
+    }
+}
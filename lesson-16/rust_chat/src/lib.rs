@@ -1,35 +1,81 @@
-use std::io::Cursor;
-use std::path::Path;
-use std::{fs, io};
+use std::io;
 
-use image::io::Reader as ImageReader;
-use image::{DynamicImage, ImageFormat};
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use thiserror::Error;
 
+pub mod client;
+pub mod commands;
+pub mod config;
+pub mod logging;
+pub mod mime;
+pub mod otlp;
+pub mod ratelimit;
+pub mod storage;
+
+/// Largest message, in bytes, the server and clients will read, anything longer closes the connection.
+///
+/// Files and photos are sent in the message itself, so this also limits their size.
+pub const MAX_MESSAGE_LEN: usize = 32 * 1024 * 1024;
+
+/// Largest message clients send, leaving room for the id and timestamp the server adds before broadcasting it.
+pub const MAX_SEND_LEN: usize = MAX_MESSAGE_LEN - 1024;
+
 /// Struct for handling messages from a specific user.
+///
+/// `id` and `timestamp` are assigned by the server on receipt, clients should leave them empty.
+/// `reply_to` is the id of the message being replied to, if any.
 #[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct UserMessage {
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
     pub username: Option<String>,
     pub message: Message,
+    #[serde(default)]
+    pub reply_to: Option<i64>,
 }
 
-/// Struct for handling different message types.
+impl UserMessage {
+    /// Create a new message to send, the server fills in the id and timestamp.
+    pub fn new(username: Option<String>, message: Message) -> Self {
+        UserMessage {
+            id: None,
+            timestamp: None,
+            username,
+            message,
+            reply_to: None,
+        }
+    }
+}
+
+/// The messages sent between the server and clients.
 ///
-/// # Examples
-/// ```
-/// use rust_chat::Message;
-/// // Should give a Message::Text containing "Hello World"
-/// Message::try_from(String::from("Hello World"));
-/// ```
+/// Client input is parsed into a `commands::ClientCommand` first, which turns into one of these when it's sent.
+/// Replies are `Text` with `UserMessage::reply_to` set.
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Message {
-    File { name: String, data: String },
+    /// A file of any type, `mime` is detected from `data` by the sender, see `mime::detect`.
+    File { name: String, mime: String, data: Vec<u8> },
     Photo { data: Vec<u8> },
     Text(String),
-    SetUser { username: Option<String> },
-    Stop,
+    Edit { id: i64, new_text: String },
+    Delete { id: i64 },
+}
+
+impl Message {
+    /// The name of the message variant, as stored in the `kind` column of the DB.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::File { .. } => "File",
+            Message::Photo { .. } => "Photo",
+            Message::Text(_) => "Text",
+            Message::Edit { .. } => "Edit",
+            Message::Delete { .. } => "Delete",
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -40,98 +86,11 @@ pub enum MessageError {
     FileReadFailed(#[from] io::Error),
     #[error("Unsupported image format.")]
     UnsupportedImage(#[from] image::ImageError),
+    #[error("Invalid message id: {0}")]
+    InvalidId(String),
+    #[error("Usage: {0}")]
+    Usage(String),
+    #[error("Unknown command {0}, see .help for a list of commands.")]
+    UnknownCommand(String),
 }
 
-impl TryFrom<String> for Message {
-    type Error = MessageError;
-
-    fn try_from(value: String) -> Result<Self, Self::Error> {
-        if value.starts_with(".") {
-            // handle command
-            let split_data: Vec<_> = value.splitn(2, " ").collect();
-            return match split_data[0] {
-                ".stop" => Ok(Message::Stop),
-                ".file" => {
-                    let filename = split_data[1];
-                    let file_path = Path::new(filename);
-                    if !file_path.exists() {
-                        return Err(MessageError::FileNotFound(
-                            file_path.to_string_lossy().to_string(),
-                        ));
-                    }
-                    return Ok(Message::File {
-                        // Unwrap is fine here, we've already checked that it exists
-                        name: file_path.file_name().unwrap().to_string_lossy().to_string(),
-                        data: fs::read_to_string(&filename)?,
-                    });
-                }
-                ".image" => {
-                    let filename = split_data[1];
-                    let file_path = Path::new(filename);
-
-                    let img: DynamicImage = ImageReader::open(file_path)?.decode()?;
-                    let mut buf = Vec::new();
-                    img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
-                    if file_path.exists() {
-                        return Ok(Message::Photo { data: buf });
-                    };
-                    Ok(Message::Text(value))
-                }
-                ".user" => {
-                    return if split_data.len() > 1 {
-                        let name = split_data[1];
-                        Ok(Message::SetUser {
-                            username: Some(name.to_string()),
-                        })
-                    } else {
-                        Ok(Message::SetUser { username: None })
-                    }
-                }
-                _ => Ok(Message::Text(value)),
-            };
-        }
-        Ok(Message::Text(value))
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::Message;
-    use std::error::Error;
-
-    #[test]
-    fn test_text_message() -> Result<(), Box<dyn Error>> {
-        let value = String::from("hello");
-        let message = Message::try_from(value.clone())?;
-        let expected = Message::Text(value.clone());
-        assert_eq!(message, expected);
-        Ok(())
-    }
-
-    #[test]
-    fn test_stop_message() -> Result<(), Box<dyn Error>> {
-        let value = String::from(".stop");
-        let message = Message::try_from(value.clone())?;
-        let expected = Message::Stop;
-        assert_eq!(message, expected);
-        Ok(())
-    }
-    #[test]
-    fn test_set_user_message() -> Result<(), Box<dyn Error>> {
-        let value = String::from(".user Custom");
-        let message = Message::try_from(value.clone())?;
-        let expected = Message::SetUser {
-            username: Some(String::from("Custom")),
-        };
-        assert_eq!(message, expected);
-        Ok(())
-    }
-    #[test]
-    fn test_set_user_empty_message() -> Result<(), Box<dyn Error>> {
-        let value = String::from(".user");
-        let message = Message::try_from(value.clone())?;
-        let expected = Message::SetUser { username: None };
-        assert_eq!(message, expected);
-        Ok(())
-    }
-}
@@ -0,0 +1,226 @@
+use std::io;
+use std::path::Path;
+
+use opentelemetry::trace::TraceError;
+use thiserror::Error;
+use tracing::subscriber::SetGlobalDefaultError;
+use tracing_appender::rolling::{self, RollingFileAppender};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::reload;
+use tracing_subscriber::{Layer, Registry};
+
+use crate::otlp;
+
+/// Logging arguments shared by all the binaries, flatten them into their `Args`.
+#[derive(clap::Args, Debug, Clone)]
+pub struct LogArgs {
+    /// Log filter, a default level and optional per-module levels, e.g. `info,server::db=debug`.
+    #[arg(long, default_value = "info")]
+    pub loglevel: Targets,
+    /// Format of log lines.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+    /// When to start a new log file.
+    #[arg(long, value_enum, default_value_t = Rotation::Never)]
+    pub log_rotation: Rotation,
+    /// Number of rotated log files to keep, older ones are deleted.
+    #[arg(long, default_value_t = 7)]
+    pub log_max_files: usize,
+    /// Only log to the log file, not to stdout.
+    #[arg(long)]
+    pub no_log_stdout: bool,
+    /// Export spans to an OTLP collector over HTTP, e.g. `http://localhost:4318`.
+    #[arg(long)]
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, including the fields of any enclosing spans.
+    Json,
+}
+
+/// When to start a new log file, which is named after the log file with the date, or date and time, appended.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    /// Always log to the same file.
+    Never,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+}
+
+impl From<Rotation> for rolling::Rotation {
+    fn from(rotation: Rotation) -> Self {
+        match rotation {
+            Rotation::Never => rolling::Rotation::NEVER,
+            Rotation::Minutely => rolling::Rotation::MINUTELY,
+            Rotation::Hourly => rolling::Rotation::HOURLY,
+            Rotation::Daily => rolling::Rotation::DAILY,
+            Rotation::Weekly => rolling::Rotation::WEEKLY,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum LoggingError {
+    #[error("Failed to open log file: {0}")]
+    File(#[from] rolling::InitError),
+    #[error("Failed to set global subscriber: {0}")]
+    SetGlobalDefault(#[from] SetGlobalDefaultError),
+    #[error("Failed to set up OTLP export: {0}")]
+    Otlp(#[from] TraceError),
+}
+
+/// Changes the log filter of a running program, returned by `init`.
+#[derive(Clone)]
+pub struct LogHandle(Vec<reload::Handle<Targets, Registry>>);
+
+impl LogHandle {
+    /// Replace the `--loglevel` filter on every output.
+    pub fn set_filter(&self, filter: &Targets) -> Result<(), reload::Error> {
+        for handle in &self.0 {
+            handle.reload(filter.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Set up the global subscriber, logging to `logfile` and stdout as configured by `args`.
+///
+/// The log file is appended to, not truncated, and rotated according to `--log-rotation`.
+/// With `--otlp-endpoint`, spans are also exported, with `service` as their source, see `otlp::layer`.
+pub fn init(args: &LogArgs, logfile: &Path, service: &str) -> Result<LogHandle, LoggingError> {
+    let file = log_file(logfile, args.log_rotation, args.log_max_files)?;
+    // each output has its own filter, so they all need their own handle to change it
+    let mut handles = Vec::new();
+    let mut filter = || {
+        let (filter, handle) = reload::Layer::new(args.loglevel.clone());
+        handles.push(handle);
+        filter
+    };
+
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+    let file_layer = tracing_subscriber::fmt::layer().with_ansi(false).with_writer(file);
+    layers.push(match args.log_format {
+        LogFormat::Text => file_layer.with_filter(filter()).boxed(),
+        LogFormat::Json => file_layer.json().with_filter(filter()).boxed(),
+    });
+    if !args.no_log_stdout {
+        let stdout_layer = tracing_subscriber::fmt::layer().with_writer(io::stdout);
+        layers.push(match args.log_format {
+            LogFormat::Text => stdout_layer.with_filter(filter()).boxed(),
+            LogFormat::Json => stdout_layer.json().with_filter(filter()).boxed(),
+        });
+    }
+    if let Some(endpoint) = &args.otlp_endpoint {
+        layers.push(otlp::layer(endpoint, service)?.with_filter(filter()).boxed());
+    }
+    tracing::subscriber::set_global_default(Registry::default().with(layers))?;
+    Ok(LogHandle(handles))
+}
+
+/// The appender for `logfile`, keeping at most `max_files` rotated files.
+fn log_file(logfile: &Path, rotation: Rotation, max_files: usize) -> Result<RollingFileAppender, rolling::InitError> {
+    let dir = match logfile.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut builder = RollingFileAppender::builder()
+        .rotation(rotation.into())
+        .filename_prefix(logfile.file_name().unwrap_or_default().to_string_lossy());
+    // the current file is never deleted, so there's no limit without rotation
+    if rotation != Rotation::Never {
+        builder = builder.max_log_files(max_files.max(1));
+    }
+    builder.build(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+    use tracing::{event, span, Level};
+    use tracing_subscriber::fmt::MakeWriter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    use crate::logging::{self, Rotation};
+
+    #[test]
+    fn test_existing_file_is_appended() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("test.log");
+        fs::write(&path, "before restart\n")?;
+        let file = logging::log_file(&path, Rotation::Never, 2)?;
+        file.make_writer().write_all(b"after restart\n")?;
+        assert_eq!(fs::read_to_string(&path)?, "before restart\nafter restart\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_rotated_file_name() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let file = logging::log_file(&dir.path().join("test.log"), Rotation::Daily, 2)?;
+        file.make_writer().write_all(b"hello\n")?;
+        let names: Vec<String> = fs::read_dir(dir.path())?
+            .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().to_string()))
+            .collect::<Result<_, _>>()?;
+        assert_eq!(names.len(), 1);
+        assert!(names[0].starts_with("test.log."));
+        Ok(())
+    }
+
+    /// Collects everything written to it, to check the output of a layer.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[test]
+    fn test_json_format() -> Result<(), Box<dyn Error>> {
+        let buffer = Buffer::default();
+        let layer = tracing_subscriber::fmt::layer().json().with_writer(buffer.clone());
+        let subscriber = Registry::default().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let connection = span!(Level::INFO, "connection", id = 7, username = tracing::field::Empty);
+            let _entered = connection.enter();
+            connection.record("username", "alice");
+            event!(Level::WARN, bytes = 12, "hello");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone())?;
+        let line: Value = serde_json::from_str(output.trim())?;
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["fields"]["message"], "hello");
+        assert_eq!(line["fields"]["bytes"], 12);
+        assert_eq!(line["spans"][0]["name"], "connection");
+        assert_eq!(line["spans"][0]["id"], 7);
+        assert_eq!(line["spans"][0]["username"], "alice");
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+/// Mime type for content that isn't recognised, and isn't text.
+pub const OCTET_STREAM: &str = "application/octet-stream";
+
+/// Signatures at the start of common file formats, checked in order.
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"PK\x05\x06", "application/zip"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"BZh", "application/x-bzip2"),
+    (b"\xfd7zXZ\x00", "application/x-xz"),
+    (b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (b"\x28\xb5\x2f\xfd", "application/zstd"),
+    (b"\x7fELF", "application/x-executable"),
+    (b"ID3", "audio/mpeg"),
+    (b"OggS", "audio/ogg"),
+    (b"fLaC", "audio/flac"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"SQLite format 3\x00", "application/vnd.sqlite3"),
+];
+
+/// Detect the mime type of a file from its content, rather than trusting its name.
+///
+/// Falls back to `text/plain` for UTF-8 without control characters, and `application/octet-stream` otherwise.
+///
+/// # Examples
+/// ```
+/// use rust_chat::mime;
+///
+/// assert_eq!(mime::detect(b"%PDF-1.7"), "application/pdf");
+/// assert_eq!(mime::detect(b"hello"), "text/plain");
+/// ```
+pub fn detect(data: &[u8]) -> &'static str {
+    if let Some((_, mime)) = SIGNATURES.iter().find(|(signature, _)| data.starts_with(signature)) {
+        return mime;
+    }
+    // formats with a container header, and the type at an offset
+    if data.len() >= 12 && data.starts_with(b"RIFF") {
+        match &data[8..12] {
+            b"WEBP" => return "image/webp",
+            b"WAVE" => return "audio/wav",
+            b"AVI " => return "video/x-msvideo",
+            _ => {}
+        }
+    }
+    if data.len() >= 12 && &data[4..8] == b"ftyp" {
+        return match &data[8..12] {
+            b"avif" => "image/avif",
+            b"heic" | b"heix" => "image/heic",
+            b"M4A " => "audio/mp4",
+            b"qt  " => "video/quicktime",
+            _ => "video/mp4",
+        };
+    }
+    if data.len() >= 262 && &data[257..262] == b"ustar" {
+        return "application/x-tar";
+    }
+    if is_bmp(data) {
+        return "image/bmp";
+    }
+    if is_text(data) {
+        return "text/plain";
+    }
+    OCTET_STREAM
+}
+
+/// Whether `data` starts with a BMP header, "BM" alone is too common at the start of text to go by.
+///
+/// `data` may only be the start of the file, so the file size only has to cover it, not match it.
+fn is_bmp(data: &[u8]) -> bool {
+    if data.len() < 18 || !data.starts_with(b"BM") {
+        return false;
+    }
+    let u32_at = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+    let (file_size, reserved, pixels_offset, dib_size) = (u32_at(2), u32_at(6), u32_at(10), u32_at(14));
+    // the DIB header sizes of the OS/2 and Windows BITMAP*HEADER versions
+    matches!(dib_size, 12 | 40 | 52 | 56 | 64 | 108 | 124)
+        && reserved == 0
+        && pixels_offset >= 14 + dib_size
+        && file_size >= pixels_offset
+        && file_size as usize >= data.len()
+}
+
+/// Whether `data` looks like text, valid UTF-8 without control characters other than whitespace.
+fn is_text(data: &[u8]) -> bool {
+    std::str::from_utf8(data).is_ok_and(|text| {
+        text.chars()
+            .all(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t' | '\x0c'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mime::{self, OCTET_STREAM};
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(mime::detect(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(mime::detect(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3"), "application/pdf");
+        assert_eq!(mime::detect(b"PK\x03\x04\x14\0\0\0"), "application/zip");
+        assert_eq!(mime::detect(b"RIFF\0\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(mime::detect(b"\0\0\0\x18ftypmp42\0\0\0\0"), "video/mp4");
+
+        // a 1x1 24-bit BMP, with a BITMAPINFOHEADER
+        let mut bmp = b"BM\x3a\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0".to_vec();
+        bmp.resize(0x3a, 0);
+        assert_eq!(mime::detect(&bmp), "image/bmp");
+        assert_eq!(mime::detect(&bmp[..20]), "image/bmp");
+        bmp[14] = 41;
+        assert_eq!(mime::detect(&bmp), OCTET_STREAM);
+        assert_eq!(mime::detect(b"BMW drivers wanted"), "text/plain");
+        assert_eq!(mime::detect(b"BM\xff\xff\xff\xff\0\0\0\0\x36\0\0\0\x28\0\0"), OCTET_STREAM);
+
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(mime::detect(&tar), "application/x-tar");
+
+        assert_eq!(mime::detect("héllo\r\n\tworld".as_bytes()), "text/plain");
+        assert_eq!(mime::detect(b""), "text/plain");
+        assert_eq!(mime::detect(b"\xff\xfe\x00\x01"), OCTET_STREAM);
+        assert_eq!(mime::detect(b"text with a \0 in it"), OCTET_STREAM);
+    }
+}
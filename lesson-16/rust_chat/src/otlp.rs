@@ -0,0 +1,138 @@
+use std::sync::OnceLock;
+use std::thread;
+
+use opentelemetry::trace::{TraceError, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// Targets the exporter and its HTTP client log with, which aren't exported so they can't feed back into it.
+const EXPORTER_TARGETS: &[&str] = &["opentelemetry", "reqwest", "hyper", "h2", "tower"];
+
+/// The provider behind the layer, kept so `shutdown` can flush it.
+static PROVIDER: OnceLock<TracerProvider> = OnceLock::new();
+
+/// Layer exporting spans, and the events logged in them, to an OTLP collector at `endpoint`, e.g.
+/// `http://localhost:4318`, with `service` as their source.
+///
+/// Spans are batched and posted as OTLP/HTTP protobuf to `<endpoint>/v1/traces` from a task on the Tokio runtime,
+/// so this must be called from within one. Call `shutdown` before exiting, to send the spans still waiting.
+pub fn layer<S>(endpoint: &str, service: &str) -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(traces_endpoint(endpoint))
+        .build()?;
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::new([KeyValue::new("service.name", service.to_string())]))
+        .build();
+    let tracer = provider.tracer(service.to_string());
+    // there's one subscriber per process, so only ever one provider to flush
+    let _ = PROVIDER.set(provider);
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(|metadata| {
+            !EXPORTER_TARGETS
+                .iter()
+                .any(|target| metadata.target().starts_with(target))
+        })))
+}
+
+/// The collector URL for traces, the exporter uses the endpoint it's given as is.
+fn traces_endpoint(endpoint: &str) -> String {
+    format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+}
+
+/// Send any spans still waiting to the collector, and stop exporting.
+///
+/// Does nothing if `layer` wasn't used. The export runs on the Tokio runtime while this waits for it, so the runtime
+/// must be multi threaded.
+pub fn shutdown() -> Result<(), TraceError> {
+    let Some(provider) = PROVIDER.get() else {
+        return Ok(());
+    };
+    // wait on a thread of our own, blocking a runtime thread could keep the export task from running
+    thread::scope(|scope| scope.spawn(|| provider.shutdown()).join())
+        .unwrap_or_else(|_| Err(TraceError::from("exporter panicked while shutting down")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use tracing::{event, info_span, Level};
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::Registry;
+
+    use crate::otlp;
+
+    #[test]
+    fn test_traces_endpoint() {
+        assert_eq!(otlp::traces_endpoint("http://localhost:4318"), "http://localhost:4318/v1/traces");
+        assert_eq!(otlp::traces_endpoint("http://collector/otlp/"), "http://collector/otlp/v1/traces");
+    }
+
+    /// Accept a single OTLP request, responding with a `200` and returning the request line and body.
+    fn collector_stub(listener: TcpListener) -> Result<(String, Vec<u8>), Box<dyn Error + Send + Sync>> {
+        let (stream, _) = listener.accept()?;
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header)?;
+            if header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    length = value.trim().parse()?;
+                }
+            }
+        }
+        let mut body = vec![0u8; length];
+        reader.read_exact(&mut body)?;
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")?;
+        Ok((request_line.trim().to_string(), body))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_on_shutdown() -> Result<(), Box<dyn Error>> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let endpoint = format!("http://{}", listener.local_addr()?);
+        let collector = thread::spawn(move || collector_stub(listener));
+
+        let subscriber = Registry::default().with(otlp::layer(&endpoint, "test-service")?);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("connection", id = 7);
+            let _entered = span.enter();
+            event!(Level::INFO, "hello");
+        });
+        // the batch isn't full, so it's only sent when flushed
+        otlp::shutdown()?;
+
+        let (request_line, body) = collector.join().unwrap().map_err(|e| e.to_string())?;
+        assert_eq!(request_line, "POST /v1/traces HTTP/1.1");
+        // protobuf strings are stored as is
+        let contains = |needle: &[u8]| body.windows(needle.len()).any(|window| window == needle);
+        assert!(contains(b"test-service"));
+        assert!(contains(b"connection"));
+        assert!(contains(b"hello"));
+        Ok(())
+    }
+}
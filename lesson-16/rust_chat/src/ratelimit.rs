@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Messages each client may send per minute, 0 for no limit.
+///
+/// This is read for every message, so it can be changed by reloading the config while clients are connected.
+pub static MESSAGES_PER_MINUTE: AtomicU32 = AtomicU32::new(0);
+
+pub fn set_messages_per_minute(limit: u32) {
+    MESSAGES_PER_MINUTE.store(limit, Ordering::Relaxed);
+}
+
+/// Counts the messages from a single client, in fixed one minute windows.
+pub struct RateLimiter {
+    window_start: Instant,
+    count: u32,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            window_start: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Count a message, returning whether it's within the current `MESSAGES_PER_MINUTE`.
+    pub fn allow(&mut self) -> bool {
+        self.allow_at(Instant::now(), MESSAGES_PER_MINUTE.load(Ordering::Relaxed))
+    }
+
+    fn allow_at(&mut self, now: Instant, limit: u32) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(60) {
+            self.window_start = now;
+            self.count = 0;
+        }
+        self.count += 1;
+        limit == 0 || self.count <= limit
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::ratelimit::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new();
+        assert!(limiter.allow_at(start, 2));
+        assert!(limiter.allow_at(start, 2));
+        assert!(!limiter.allow_at(start + Duration::from_secs(30), 2));
+        // a new window starts after a minute
+        assert!(limiter.allow_at(start + Duration::from_secs(61), 2));
+        // a limit of 0 disables it
+        assert!(limiter.allow_at(start + Duration::from_secs(62), 0));
+    }
+}
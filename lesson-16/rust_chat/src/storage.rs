@@ -0,0 +1,116 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Longest file name, in bytes, that `sanitize_filename` will return.
+const MAX_NAME_LEN: usize = 200;
+
+/// Turn a name received from another client into a safe name for a single file.
+///
+/// Any directory components are dropped, so names like `../../.bashrc` can't escape the
+/// directory they are saved to, and characters that are reserved on common file systems are replaced.
+/// Quotes are replaced too, so the name can go in a quoted header value or HTML attribute as is.
+pub fn sanitize_filename(name: &str) -> String {
+    // treat both separators as separators, the sender may be on another OS
+    let last = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let mut sanitized: String = last
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '\'' | '|' | '?' | '*' => '_',
+            c => c,
+        })
+        .collect();
+    // leading dots would make hidden files, or `.`/`..`
+    sanitized = sanitized.trim_start_matches('.').trim().to_string();
+
+    if sanitized.len() > MAX_NAME_LEN {
+        let mut end = MAX_NAME_LEN;
+        while !sanitized.is_char_boundary(end) {
+            end -= 1;
+        }
+        sanitized.truncate(end);
+    }
+    if sanitized.is_empty() {
+        return String::from("file");
+    }
+    sanitized
+}
+
+/// Write `data` to a new file in `dir`, never overwriting an existing file.
+///
+/// The name is sanitized first, and if a file with that name already exists a numbered suffix is
+/// added, e.g. `notes (1).txt`.  Returns the path that was written.
+pub fn write_new(dir: &Path, name: &str, data: &[u8]) -> io::Result<PathBuf> {
+    let name = sanitize_filename(name);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name.as_str(), None),
+    };
+
+    for attempt in 0.. {
+        let candidate = match (attempt, extension) {
+            (0, _) => name.clone(),
+            (n, Some(extension)) => format!("{stem} ({n}).{extension}"),
+            (n, None) => format!("{stem} ({n})"),
+        };
+        let path = dir.join(candidate);
+        // `create_new` fails if the file exists, so two writers can't race for the same name
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(data)?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("ran out of file name suffixes")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+
+    use crate::storage::{sanitize_filename, write_new};
+
+    #[test]
+    fn test_sanitize_traversal() {
+        assert_eq!(sanitize_filename("../../etc/passwd"), "passwd");
+        assert_eq!(sanitize_filename("..\\..\\Windows\\win.ini"), "win.ini");
+        assert_eq!(sanitize_filename("/absolute/path.txt"), "path.txt");
+        assert_eq!(sanitize_filename(".."), "file");
+        assert_eq!(sanitize_filename("../"), "file");
+        assert_eq!(sanitize_filename(".bashrc"), "bashrc");
+    }
+
+    #[test]
+    fn test_sanitize_reserved_characters() {
+        assert_eq!(sanitize_filename("a<b>c:d\"e|f?g*h.txt"), "a_b_c_d_e_f_g_h.txt");
+        assert_eq!(sanitize_filename("say \"hi\" it's.txt"), "say _hi_ it_s.txt");
+        assert_eq!(sanitize_filename("new\nline\0.txt"), "newline.txt");
+        assert_eq!(sanitize_filename(""), "file");
+    }
+
+    #[test]
+    fn test_sanitize_long_name() {
+        let name = "é".repeat(300);
+        let sanitized = sanitize_filename(&name);
+        assert!(sanitized.len() <= 200);
+        assert!(sanitized.chars().all(|c| c == 'é'));
+    }
+
+    #[test]
+    fn test_write_new_does_not_overwrite() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let first = write_new(dir.path(), "notes.txt", b"first")?;
+        let second = write_new(dir.path(), "../notes.txt", b"second")?;
+        assert_eq!(first, dir.path().join("notes.txt"));
+        assert_eq!(second, dir.path().join("notes (1).txt"));
+        assert_eq!(fs::read(first)?, b"first");
+        assert_eq!(fs::read(second)?, b"second");
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Row, Sqlite};
+
+use crate::ServerError;
+
+/// Create the database tables, and add any columns missing from databases created by older versions.
+pub async fn setup(db: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS messages \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        username VARCHAR(250), \
+        message VARCHAR(250) NOT NULL, \
+        kind VARCHAR(16) NOT NULL DEFAULT 'Text', \
+        timestamp TEXT, \
+        reply_to INTEGER\
+    );",
+    )
+    .execute(db)
+    .await?;
+    add_column_if_missing(
+        db,
+        "messages",
+        "kind",
+        "VARCHAR(16) NOT NULL DEFAULT 'Text'",
+    )
+    .await?;
+    add_column_if_missing(db, "messages", "timestamp", "TEXT").await?;
+    add_column_if_missing(db, "messages", "reply_to", "INTEGER").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS message_edits \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        message_id INTEGER NOT NULL, \
+        old_message VARCHAR(250) NOT NULL, \
+        edited_at TEXT NOT NULL\
+    );",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// SQLite has no `ADD COLUMN IF NOT EXISTS`, so check the table info first.
+async fn add_column_if_missing(
+    db: &Pool<Sqlite>,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({table})"))
+        .fetch_all(db)
+        .await?;
+    let exists = columns
+        .iter()
+        .any(|row| row.get::<String, &str>("name") == column);
+    if !exists {
+        sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+        ))
+        .execute(db)
+        .await?;
+    }
+    Ok(())
+}
+
+/// Store a message, returning the id assigned to it.
+///
+/// Replies are only stored if the message they reply to exists.
+pub async fn insert_message(
+    db: &Pool<Sqlite>,
+    username: &Option<String>,
+    content: &str,
+    kind: &str,
+    timestamp: DateTime<Utc>,
+    reply_to: Option<i64>,
+) -> Result<i64, ServerError> {
+    if let Some(parent) = reply_to {
+        sqlx::query("SELECT id FROM messages WHERE id=$1")
+            .bind(parent)
+            .fetch_optional(db)
+            .await
+            .map_err(|_| ServerError::DBReadFailed)?
+            .ok_or(ServerError::MessageNotFound(parent))?;
+    }
+    let result = sqlx::query(
+        "INSERT INTO messages (username, message, kind, timestamp, reply_to) \
+        VALUES ($1, $2, $3, $4, $5)",
+    )
+    .bind(username)
+    .bind(content)
+    .bind(kind)
+    .bind(timestamp)
+    .bind(reply_to)
+    .execute(db)
+    .await
+    .map_err(|_| ServerError::DBWriteFailed)?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Check that a message exists, and that it was sent by `username`, returning the message kind.
+///
+/// Anonymous messages have no owner, so they can never be modified.
+async fn check_owner(
+    db: &Pool<Sqlite>,
+    id: i64,
+    username: &Option<String>,
+) -> Result<String, ServerError> {
+    let row = sqlx::query("SELECT username, kind FROM messages WHERE id=$1")
+        .bind(id)
+        .fetch_optional(db)
+        .await
+        .map_err(|_| ServerError::DBReadFailed)?
+        .ok_or(ServerError::MessageNotFound(id))?;
+    let owner: Option<String> = row.get("username");
+    if owner.is_none() || &owner != username {
+        return Err(ServerError::NotMessageOwner(id));
+    }
+    Ok(row.get("kind"))
+}
+
+/// Replace the text of a message, keeping the old text in `message_edits`.
+pub async fn edit_message(
+    db: &Pool<Sqlite>,
+    id: i64,
+    username: &Option<String>,
+    new_text: &str,
+) -> Result<(), ServerError> {
+    if check_owner(db, id, username).await? != "Text" {
+        return Err(ServerError::MessageNotEditable(id));
+    }
+
+    let mut tx = db.begin().await.map_err(|_| ServerError::DBWriteFailed)?;
+    sqlx::query(
+        "INSERT INTO message_edits (message_id, old_message, edited_at) \
+        SELECT id, message, $2 FROM messages WHERE id=$1",
+    )
+    .bind(id)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| ServerError::DBWriteFailed)?;
+    sqlx::query("UPDATE messages SET message=$2 WHERE id=$1")
+        .bind(id)
+        .bind(new_text)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ServerError::DBWriteFailed)?;
+    tx.commit().await.map_err(|_| ServerError::DBWriteFailed)
+}
+
+/// Delete a message along with its edit history.
+pub async fn delete_message(
+    db: &Pool<Sqlite>,
+    id: i64,
+    username: &Option<String>,
+) -> Result<(), ServerError> {
+    check_owner(db, id, username).await?;
+
+    let mut tx = db.begin().await.map_err(|_| ServerError::DBWriteFailed)?;
+    sqlx::query("DELETE FROM message_edits WHERE message_id=$1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ServerError::DBWriteFailed)?;
+    sqlx::query("DELETE FROM messages WHERE id=$1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ServerError::DBWriteFailed)?;
+    tx.commit().await.map_err(|_| ServerError::DBWriteFailed)
+}
+
+/// Number of messages removed by `prune_messages`, for each part of the retention policy.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub by_age: u64,
+    pub by_count: u64,
+}
+
+/// Delete messages older than `cutoff`, then the oldest messages beyond `max_messages`,
+/// along with their edit history.
+///
+/// Messages stored before timestamps were recorded are only pruned by count.
+pub async fn prune_messages(
+    db: &Pool<Sqlite>,
+    cutoff: Option<DateTime<Utc>>,
+    max_messages: Option<i64>,
+) -> Result<PruneStats, ServerError> {
+    let mut stats = PruneStats::default();
+    let mut tx = db.begin().await.map_err(|_| ServerError::DBWriteFailed)?;
+    if let Some(cutoff) = cutoff {
+        stats.by_age = sqlx::query("DELETE FROM messages WHERE timestamp < $1")
+            .bind(cutoff)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| ServerError::DBWriteFailed)?
+            .rows_affected();
+    }
+    if let Some(max_messages) = max_messages {
+        stats.by_count = sqlx::query(
+            "DELETE FROM messages WHERE id NOT IN \
+            (SELECT id FROM messages ORDER BY id DESC LIMIT $1)",
+        )
+        .bind(max_messages)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ServerError::DBWriteFailed)?
+        .rows_affected();
+    }
+    sqlx::query("DELETE FROM message_edits WHERE message_id NOT IN (SELECT id FROM messages)")
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| ServerError::DBWriteFailed)?;
+    tx.commit().await.map_err(|_| ServerError::DBWriteFailed)?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::Duration;
+
+    use chrono::Utc;
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::{Pool, Row, Sqlite};
+
+    use crate::db;
+    use crate::db::PruneStats;
+    use crate::ServerError;
+
+    pub(crate) async fn test_db() -> Pool<Sqlite> {
+        // every connection to an in-memory DB gets a new database, so only allow one
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        db::setup(&db).await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_edit_keeps_history() -> Result<(), ServerError> {
+        let db = test_db().await;
+        let user = Some(String::from("alice"));
+        let id = db::insert_message(&db, &user, "helo", "Text", Utc::now(), None).await?;
+        db::edit_message(&db, id, &user, "hello").await?;
+
+        let row = sqlx::query("SELECT message FROM messages WHERE id=$1")
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, &str>("message"), "hello");
+        let row = sqlx::query("SELECT old_message FROM message_edits WHERE message_id=$1")
+            .bind(id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(row.get::<String, &str>("old_message"), "helo");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_edit_checks_owner() -> Result<(), ServerError> {
+        let db = test_db().await;
+        let id = db::insert_message(
+            &db,
+            &Some(String::from("alice")),
+            "hi",
+            "Text",
+            Utc::now(),
+            None,
+        )
+        .await?;
+        let result = db::edit_message(&db, id, &Some(String::from("mallory")), "owned").await;
+        assert!(matches!(result, Err(ServerError::NotMessageOwner(_))));
+
+        let anon_id = db::insert_message(&db, &None, "hi", "Text", Utc::now(), None).await?;
+        let result = db::delete_message(&db, anon_id, &None).await;
+        assert!(matches!(result, Err(ServerError::NotMessageOwner(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reply_requires_parent() -> Result<(), ServerError> {
+        let db = test_db().await;
+        let user = Some(String::from("alice"));
+        let id = db::insert_message(&db, &user, "hi", "Text", Utc::now(), None).await?;
+        db::insert_message(&db, &user, "hi again", "Text", Utc::now(), Some(id)).await?;
+        let result = db::insert_message(&db, &user, "what?", "Text", Utc::now(), Some(42)).await;
+        assert!(matches!(result, Err(ServerError::MessageNotFound(42))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_message() -> Result<(), ServerError> {
+        let db = test_db().await;
+        let user = Some(String::from("alice"));
+        let id = db::insert_message(&db, &user, "hi", "Text", Utc::now(), None).await?;
+        db::delete_message(&db, id, &user).await?;
+        let result = db::delete_message(&db, id, &user).await;
+        assert!(matches!(result, Err(ServerError::MessageNotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_messages() -> Result<(), ServerError> {
+        let db = test_db().await;
+        let user = Some(String::from("alice"));
+        let old = Utc::now() - Duration::days(10);
+        let id = db::insert_message(&db, &user, "old", "Text", old, None).await?;
+        db::edit_message(&db, id, &user, "older").await?;
+        for text in ["one", "two", "three"] {
+            db::insert_message(&db, &user, text, "Text", Utc::now(), None).await?;
+        }
+
+        let stats =
+            db::prune_messages(&db, Some(Utc::now() - Duration::days(7)), Some(2)).await?;
+        assert_eq!(stats, PruneStats { by_age: 1, by_count: 1 });
+        let rows = sqlx::query("SELECT message FROM messages ORDER BY id")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        let remaining: Vec<String> = rows.iter().map(|row| row.get("message")).collect();
+        assert_eq!(remaining, ["two", "three"]);
+        let edits = sqlx::query("SELECT id FROM message_edits")
+            .fetch_all(&db)
+            .await
+            .unwrap();
+        assert!(edits.is_empty());
+        Ok(())
+    }
+}
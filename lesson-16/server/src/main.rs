@@ -1,35 +1,124 @@
-use std::fs::File;
+use std::path::PathBuf;
 use std::{env, io};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::Utc;
 use clap::Parser;
-use sqlx::{migrate::MigrateDatabase, Pool, Sqlite, SqlitePool, Row};
+use sqlx::{migrate::MigrateDatabase, Pool, Sqlite, SqlitePool};
 use thiserror::Error;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::select;
-use tokio::sync::broadcast::{channel, Receiver, Sender};
-use tracing::level_filters::LevelFilter;
-use tracing::{event, Level};
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{Layer, Registry};
+use tokio::sync::broadcast::{channel, error::RecvError, Receiver, Sender};
+use tracing::{event, field, info_span, Instrument, Level, Span};
 
-use rust_chat::{Message, UserMessage};
+use rust_chat::config::{ConfigError, Loader, Validate};
+use rust_chat::logging::{self, LogArgs, LogHandle};
+use rust_chat::otlp;
+use rust_chat::ratelimit;
+use rust_chat::{Message, UserMessage, MAX_MESSAGE_LEN};
 
-/// Struct for parsing args.
-#[derive(Parser, Debug)]
-struct Args {
+mod db;
+mod metrics;
+mod retention;
+
+/// Id of the next accepted connection, used to tell clients apart in logs.
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// How often to check the config file for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Server config, from the config file, `CHAT_SERVER_*` environment variables, and the command line.
+#[derive(Parser, Debug, Clone)]
+struct Config {
+    /// TOML config file, with settings named after the arguments, e.g. `db_path = "chat.db"`.
+    #[arg(long)]
+    config: Option<PathBuf>,
     #[arg(short, long, default_value_t = String::from("0.0.0.0"))]
     address: String,
     #[arg(short, long, default_value_t = 11111)]
     port: u16,
-    #[arg(long, default_value = "info")]
-    loglevel: LevelFilter,
+    #[command(flatten)]
+    log: LogArgs,
     #[arg(long, default_value_t = String::from("server.log"))]
     logfile: String,
     #[arg(long, default_value_t = String::from("sqlite.db"))]
     db_path: String,
+    /// Delete messages older than this many days, 0 to keep them forever.
+    #[arg(long, default_value_t = 0)]
+    retention_days: u64,
+    /// Keep at most this many messages, deleting the oldest first, 0 for no limit.
+    #[arg(long, default_value_t = 0)]
+    retention_max_messages: u64,
+    /// How often to apply the retention policy, in seconds.
+    #[arg(long, default_value_t = 3600)]
+    retention_interval_secs: u64,
+    /// Serve Prometheus metrics at `/metrics` on this port, disabled if not set.
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// Messages each client may send per minute, 0 for no limit.
+    #[arg(long, default_value_t = 0)]
+    messages_per_minute: u32,
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, value) in [("address", &self.address), ("logfile", &self.logfile), ("db_path", &self.db_path)] {
+            if value.trim().is_empty() {
+                problems.push(format!("{name} must not be empty"));
+            }
+        }
+        if self.retention_interval_secs == 0 {
+            problems.push(String::from("retention_interval_secs must be at least 1"));
+        }
+        if self.metrics_port == Some(self.port) {
+            problems.push(format!("metrics_port must differ from port, both are {}", self.port));
+        }
+        problems
+    }
+}
+
+impl Config {
+    /// Whether `other` changes any setting that is only read on startup.
+    fn needs_restart(&self, other: &Config) -> bool {
+        self.address != other.address
+            || self.port != other.port
+            || self.logfile != other.logfile
+            || self.db_path != other.db_path
+            || self.retention_days != other.retention_days
+            || self.retention_max_messages != other.retention_max_messages
+            || self.retention_interval_secs != other.retention_interval_secs
+            || self.metrics_port != other.metrics_port
+            || self.log.log_format != other.log.log_format
+            || self.log.log_rotation != other.log.log_rotation
+            || self.log.log_max_files != other.log.log_max_files
+            || self.log.no_log_stdout != other.log.no_log_stdout
+            || self.log.otlp_endpoint != other.log.otlp_endpoint
+    }
+
+    /// Apply the settings that can change while running, the log level and rate limit, from a reloaded config.
+    fn reload(&mut self, log: &LogHandle, reloaded: Result<Config, ConfigError>) {
+        let reloaded = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                event!(Level::ERROR, "Ignoring invalid config: {e}");
+                return;
+            }
+        };
+        if let Err(e) = log.set_filter(&reloaded.log.loglevel) {
+            event!(Level::ERROR, "Failed to change log level: {e}");
+        }
+        ratelimit::set_messages_per_minute(reloaded.messages_per_minute);
+        if self.needs_restart(&reloaded) {
+            event!(Level::WARN, "Config changed settings which only apply after a restart");
+        }
+        event!(Level::INFO, "Reloaded config, log level {}, {} messages per minute", reloaded.log.loglevel, reloaded.messages_per_minute);
+        *self = reloaded;
+    }
 }
 
 /// Custom server errors, used internally to communicate error states.
@@ -41,6 +130,8 @@ pub enum ServerError {
     ReadWriteInitFailed(String),
     #[error("Failed to read valid data from client {0}.")]
     ReadFailed(String),
+    #[error("Client {0} sent a message of {1} bytes, more than the limit.")]
+    MessageTooLong(String, usize),
     #[error("Client {0} closed the connection.")]
     ConnectionClosed(String),
     #[error("Failed to send message to client {0}.")]
@@ -49,51 +140,91 @@ pub enum ServerError {
     MessageSerializeFailed,
     #[error("Failed to write to DB.")]
     DBWriteFailed,
+    #[error("Failed to read from DB.")]
+    DBReadFailed,
+    #[error("Message {0} does not exist.")]
+    MessageNotFound(i64),
+    #[error("Message {0} belongs to another user.")]
+    NotMessageOwner(i64),
+    #[error("Message {0} is not a text message, and can't be edited.")]
+    MessageNotEditable(i64),
+    #[error("This connection belongs to \"{0}\", not \"{1}\".")]
+    UsernameMismatch(String, String),
+    #[error("Client sent more than {0} messages in a minute.")]
+    RateLimited(u32),
+}
+
+impl ServerError {
+    /// The name of the variant, used to label error metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ServerError::PeerAddressUnknown => "PeerAddressUnknown",
+            ServerError::ReadWriteInitFailed(_) => "ReadWriteInitFailed",
+            ServerError::ReadFailed(_) => "ReadFailed",
+            ServerError::MessageTooLong(..) => "MessageTooLong",
+            ServerError::ConnectionClosed(_) => "ConnectionClosed",
+            ServerError::MessageSendFailed(_) => "MessageSendFailed",
+            ServerError::MessageSerializeFailed => "MessageSerializeFailed",
+            ServerError::DBWriteFailed => "DBWriteFailed",
+            ServerError::DBReadFailed => "DBReadFailed",
+            ServerError::MessageNotFound(_) => "MessageNotFound",
+            ServerError::NotMessageOwner(_) => "NotMessageOwner",
+            ServerError::MessageNotEditable(_) => "MessageNotEditable",
+            ServerError::UsernameMismatch(..) => "UsernameMismatch",
+            ServerError::RateLimited(_) => "RateLimited",
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), ServerError> {
-    let args = Args::parse();
+    let loader = Loader::new("CHAT_SERVER_");
+    let config: Config = loader.load().unwrap_or_else(|e| e.exit());
     let local_path = env::current_dir().unwrap();
 
-    // initialize logging
-    let log_subscriber = Registry::default().with({
-        let file =
-            File::create(local_path.join(args.logfile)).expect("Failed to create logfile...");
-        tracing_subscriber::fmt::layer()
-            .with_writer(file)
-            .with_writer(io::stdout)
-            .with_filter(args.loglevel)
-    });
-    tracing::subscriber::set_global_default(log_subscriber)
-        .expect("Unable to set global subscriber...");
+    let log = logging::init(&config.log, &local_path.join(&config.logfile), "server").expect("Failed to set up logging...");
+    ratelimit::set_messages_per_minute(config.messages_per_minute);
+    let mut current = config.clone();
+    loader
+        .watch(CONFIG_POLL_INTERVAL, move |reloaded| current.reload(&log, reloaded))
+        .unwrap_or_else(|e| e.exit());
 
-    let bind_addr = format!("{}:{}", args.address, args.port);
+    let bind_addr = format!("{}:{}", config.address, config.port);
 
     event!(Level::INFO, "Starting server on {bind_addr}",);
     let server = TcpListener::bind(bind_addr.clone())
         .await
-        .expect(&format!("Server failed to bind to {bind_addr}"));
+        .unwrap_or_else(|_| panic!("Server failed to bind to {bind_addr}"));
     event!(Level::INFO, "Server serving on {bind_addr}");
 
-    if !Sqlite::database_exists(&args.db_path)
+    if !Sqlite::database_exists(&config.db_path)
         .await
         .unwrap_or(false)
     {
-        event!(Level::INFO, "Creating message database: {}", &args.db_path);
-        Sqlite::create_database(&args.db_path)
+        event!(Level::INFO, "Creating message database: {}", &config.db_path);
+        Sqlite::create_database(&config.db_path)
             .await
             .expect("Unable to create message database.");
     } else {
-        event!(Level::INFO, "Message database exists: {}", &args.db_path);
+        event!(Level::INFO, "Message database exists: {}", &config.db_path);
+    }
+    let db = Arc::new(SqlitePool::connect(&config.db_path).await.unwrap());
+    db::setup(&db).await.expect("Failed to set up database.");
+
+    let retention = retention::RetentionPolicy {
+        max_age: (config.retention_days > 0)
+            .then(|| chrono::Duration::days(config.retention_days as i64)),
+        max_messages: (config.retention_max_messages > 0)
+            .then_some(config.retention_max_messages as i64),
+    };
+    if retention.is_enabled() {
+        event!(Level::INFO, "Pruning messages with {retention:?}");
+        tokio::spawn(retention::retention_task(
+            db.clone(),
+            retention,
+            Duration::from_secs(config.retention_interval_secs),
+        ));
     }
-    let db = Arc::new(SqlitePool::connect(&args.db_path).await.unwrap());
-    sqlx::query("CREATE TABLE IF NOT EXISTS messages \
-    (\
-        id INTEGER PRIMARY KEY NOT NULL, \
-        username VARCHAR(250), \
-        message VARCHAR(250) NOT NULL\
-    );").execute(&*db).await.expect("Failed to set up database.");
 
     // check DB values
     // let result = sqlx::query("SELECT id, username, message FROM messages")
@@ -104,11 +235,40 @@ async fn main() -> Result<(), ServerError> {
     //     println!("[{}]: {}", row.get::<String, &str>("username"), row.get::<String, &str>("message"));
     // }
 
+    if let Some(metrics_port) = config.metrics_port {
+        let metrics_addr = format!("{}:{metrics_port}", config.address);
+        let listener = TcpListener::bind(&metrics_addr)
+            .await
+            .unwrap_or_else(|_| panic!("Metrics server failed to bind to {metrics_addr}"));
+        event!(Level::INFO, "Serving metrics on {metrics_addr}");
+        tokio::spawn(metrics::serve(listener));
+    }
+
+    select! {
+        _ = serve(server, db) => {}
+        _ = tokio::signal::ctrl_c() => event!(Level::INFO, "Shutting down"),
+    }
+    if let Err(e) = otlp::shutdown() {
+        event!(Level::WARN, "Failed to export remaining spans: {e}");
+    }
+    Ok(())
+}
+
+/// Accept clients on `listener` forever, relaying their messages to each other.
+async fn serve(listener: TcpListener, db: Arc<Pool<Sqlite>>) {
     let (broadcast, _broadcast_recv) = channel::<(String, UserMessage)>(64);
     loop {
-        if let Ok((socket, addr)) = server.accept().await {
-            event!(Level::INFO, "Accepted client: {addr}");
-            tokio::spawn(handle_client(socket, broadcast.clone(), db.clone()));
+        if let Ok((socket, addr)) = listener.accept().await {
+            // every log line about this client is in this span, `username` is filled in once they send a message
+            let span = info_span!(
+                "connection",
+                id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+                peer = %addr,
+                username = field::Empty,
+            );
+            span.in_scope(|| event!(Level::INFO, "Accepted client"));
+            metrics::CONNECTIONS.inc();
+            tokio::spawn(handle_client(socket, broadcast.clone(), db.clone()).instrument(span));
         } else {
             event!(Level::ERROR, "Unknown error while accepting new clients.");
         }
@@ -125,6 +285,7 @@ async fn handle_client(stream: TcpStream, broadcast: Sender<(String, UserMessage
     let client_send = handle_client_send(stream_write, broadcast.subscribe());
     let client_recv = handle_client_recv(stream_recv, broadcast.clone(), db);
 
+    metrics::CONNECTED_CLIENTS.inc();
     // if any of these return, they should both be stopped
     let result = select! {
         r = client_send => {
@@ -134,7 +295,9 @@ async fn handle_client(stream: TcpStream, broadcast: Sender<(String, UserMessage
             r
         }
     };
+    metrics::CONNECTED_CLIENTS.dec();
     if let Err(e) = result {
+        metrics::record_error(&e);
         if let ServerError::ConnectionClosed(_) = e {
             event!(Level::INFO, "{e}")
         } else {
@@ -144,8 +307,11 @@ async fn handle_client(stream: TcpStream, broadcast: Sender<(String, UserMessage
 }
 
 /// Handles sending data to clients.
+///
+/// Messages are framed like `rust_chat::client` expects, their length as a little endian `u32`, then the message as
+/// CBOR. Any write error closes the connection, a partly written message would leave the client out of step.
 async fn handle_client_send(
-    writer: OwnedWriteHalf,
+    mut writer: OwnedWriteHalf,
     mut broadcast: Receiver<(String, UserMessage)>,
 ) -> Result<(), ServerError> {
     let peer_address = writer
@@ -157,37 +323,38 @@ async fn handle_client_send(
         match received {
             Ok(data) => {
                 let (address, message) = data;
-                if address == peer_address {
+                // senders get their own messages back to learn the assigned id,
+                // except for files and photos, which they already have
+                if address == peer_address
+                    && matches!(message.message, Message::File { .. } | Message::Photo { .. })
+                {
                     continue;
                 }
-                loop {
-                    let msg_serialized = serde_cbor::to_vec(&message)
-                        .map_err(|_| ServerError::MessageSerializeFailed)?;
-                    let msg_length = msg_serialized.len() as u32;
-
-                    let write_res = writer.try_write(&msg_length.to_le_bytes());
-
-                    if let Err(e) = write_res {
-                        if e.kind() == io::ErrorKind::WouldBlock {
-                            continue;
-                        }
-                        event!(Level::WARN, "Error sending data to {peer_address}: {e}",);
-                        break;
-                    }
-                    writer
-                        .try_write(&msg_serialized)
-                        .map_err(|_| ServerError::MessageSendFailed(peer_address.clone()))?;
-                    break;
-                }
+                let msg_serialized = serde_cbor::to_vec(&message)
+                    .map_err(|_| ServerError::MessageSerializeFailed)?;
+                // messages were read within the limit, which is well under `u32::MAX`
+                let msg_length = msg_serialized.len() as u32;
+                writer
+                    .write_all(&msg_length.to_le_bytes())
+                    .await
+                    .map_err(|_| ServerError::MessageSendFailed(peer_address.clone()))?;
+                writer
+                    .write_all(&msg_serialized)
+                    .await
+                    .map_err(|_| ServerError::MessageSendFailed(peer_address.clone()))?;
+                metrics::BYTES_SENT.inc_by(4 + msg_serialized.len() as u64);
             }
-            Err(e) => {
-                event!(Level::ERROR, "{e}")
+            Err(RecvError::Lagged(skipped)) => {
+                event!(Level::WARN, "Client fell behind, skipped {skipped} messages")
             }
+            Err(RecvError::Closed) => return Ok(()),
         }
     }
 }
 
 /// Handles receiving data from clients.
+///
+/// Any error reading a message closes the connection, there's no telling where the next message starts after one.
 async fn handle_client_recv(
     mut reader: OwnedReadHalf,
     broadcast: Sender<(String, UserMessage)>,
@@ -197,50 +364,277 @@ async fn handle_client_recv(
         .peer_addr()
         .map_err(|_| ServerError::PeerAddressUnknown)?
         .to_string();
+    // the span created for this connection in `main`
+    let connection = Span::current();
+    let mut limiter = ratelimit::RateLimiter::new();
+    let mut username = None;
     loop {
         let mut msg_length_raw = [0u8; 4];
-        let read_result = reader.read(&mut msg_length_raw).await;
-        if let Err(e) = read_result {
-            event!(Level::WARN, "Error with {peer_address}: {e}",);
-            continue;
+        match reader.read_exact(&mut msg_length_raw).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(ServerError::ConnectionClosed(peer_address));
+            }
+            Err(_) => return Err(ServerError::ReadFailed(peer_address)),
+        }
+        let msg_len = u32::from_le_bytes(msg_length_raw) as usize;
+        if msg_len > MAX_MESSAGE_LEN {
+            return Err(ServerError::MessageTooLong(peer_address, msg_len));
         }
-        let msg_length = u32::from_le_bytes(msg_length_raw);
-        let msg_len = if let Ok(len) = usize::try_from(msg_length) {
-            len
-        } else {
-            event!(
-                Level::WARN,
-                "Failed to convert message length for {peer_address}."
-            );
-            continue;
-        };
         let mut msg_raw = vec![0u8; msg_len];
         reader
             .read_exact(&mut msg_raw)
             .await
             .map_err(|_| ServerError::ReadFailed(peer_address.clone()))?;
 
-        let msg = serde_cbor::from_slice::<UserMessage>(&msg_raw)
-            .map_err(|_| ServerError::ConnectionClosed(peer_address.clone()))?;
+        metrics::BYTES_RECEIVED.inc_by(4 + msg_len as u64);
 
-        match &msg.message {
-            Message::File { name, .. } => {
-                event!(Level::INFO, "Receiving file from {peer_address}: {name}",)
-            }
-            Message::Photo { .. } => {
-                event!(Level::INFO, "Receiving photo from {peer_address}",)
-            }
-            Message::Text(text) => {
-                event!(Level::INFO, "Got message from {peer_address}: {text}");
-                sqlx::query("INSERT INTO messages (username, message) VALUES ($1, $2)")
-                .bind(&msg.username).bind(text)
-                .execute(&mut *db.acquire().await.unwrap())
-                .await.map_err(|_| {ServerError::DBWriteFailed})?;
+        let mut msg = serde_cbor::from_slice::<UserMessage>(&msg_raw)
+            .map_err(|_| ServerError::ReadFailed(peer_address.clone()))?;
+        metrics::MESSAGES_RECEIVED.with_label_values(&[msg.message.kind()]).inc();
+        if !limiter.allow() {
+            let e = ServerError::RateLimited(ratelimit::MESSAGES_PER_MINUTE.load(Ordering::Relaxed));
+            metrics::record_error(&e);
+            event!(Level::WARN, "Dropped message: {e}");
+            continue;
+        }
+        if let Err(e) = bind_username(&mut username, &mut msg) {
+            metrics::record_error(&e);
+            event!(Level::WARN, "Rejected message: {e}");
+            continue;
+        }
+        if let Some(username) = &username {
+            connection.record("username", username.as_str());
+        }
+
+        let span = info_span!("message", kind = msg.message.kind(), id = field::Empty);
+        if handle_message(&mut msg, &db).instrument(span).await? {
+            broadcast
+                .send((peer_address.clone(), msg.clone()))
+                .map_err(|_| ServerError::MessageSendFailed(peer_address.clone()))?;
+        }
+    }
+}
+
+/// Check a message against the username bound to its connection, binding the first one the client sends.
+///
+/// Edits and deletes are checked against the message's username, so without this a client could act as any user just
+/// by naming them. Messages without a username are sent as the bound user, once there is one.
+fn bind_username(bound: &mut Option<String>, msg: &mut UserMessage) -> Result<(), ServerError> {
+    match (bound.as_ref(), msg.username.as_ref()) {
+        (None, _) => *bound = msg.username.clone(),
+        (Some(_), None) => msg.username = bound.clone(),
+        (Some(bound), Some(username)) if bound != username => {
+            return Err(ServerError::UsernameMismatch(bound.clone(), username.clone()));
+        }
+        (Some(_), Some(_)) => {}
+    }
+    Ok(())
+}
+
+/// Store a received message, and stamp it with its DB id and the time it was received.
+///
+/// Returns whether the message should be broadcast, rejected messages and client side commands aren't.
+async fn handle_message(msg: &mut UserMessage, db: &Pool<Sqlite>) -> Result<bool, ServerError> {
+    let timestamp = Utc::now();
+    msg.id = None;
+    msg.timestamp = Some(timestamp);
+    if matches!(msg.message, Message::Edit { .. } | Message::Delete { .. }) {
+        msg.reply_to = None;
+    }
+
+    let result = match &msg.message {
+        Message::File { name, mime, .. } => {
+            event!(Level::INFO, "Receiving file: {name} ({mime})");
+            db::insert_message(db, &msg.username, name, "File", timestamp, msg.reply_to)
+                .await
+                .map(Some)
+        }
+        Message::Photo { .. } => {
+            event!(Level::INFO, "Receiving photo");
+            db::insert_message(db, &msg.username, "", "Photo", timestamp, msg.reply_to)
+                .await
+                .map(Some)
+        }
+        Message::Text(text) => {
+            event!(Level::INFO, "Got message: {text}");
+            db::insert_message(db, &msg.username, text, "Text", timestamp, msg.reply_to)
+                .await
+                .map(Some)
+        }
+        Message::Edit { id, new_text } => {
+            event!(Level::INFO, "Got edit of message {id}: {new_text}");
+            // edits and deletes reference an existing message, rather than creating a new one
+            db::edit_message(db, *id, &msg.username, new_text)
+                .await
+                .map(|_| None)
+        }
+        Message::Delete { id } => {
+            event!(Level::INFO, "Got delete of message {id}");
+            db::delete_message(db, *id, &msg.username)
+                .await
+                .map(|_| None)
+        }
+    };
+    match result {
+        Ok(id) => {
+            if let Some(id) = id {
+                Span::current().record("id", id);
             }
-            _ => {}
+            msg.id = id;
+            Ok(true)
+        }
+        Err(
+            e @ (ServerError::MessageNotFound(_)
+            | ServerError::NotMessageOwner(_)
+            | ServerError::MessageNotEditable(_)),
+        ) => {
+            metrics::record_error(&e);
+            event!(Level::WARN, "Rejected message: {e}");
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+    use tokio_stream::StreamExt;
+
+    use rust_chat::client::{ChatClient, ClientError};
+    use rust_chat::{Message, UserMessage, MAX_MESSAGE_LEN};
+
+    use crate::db;
+
+    /// Start a server on a free port, with an in-memory DB, returning its address.
+    async fn start_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let db = Arc::new(db::tests::test_db().await);
+        tokio::spawn(crate::serve(listener, db));
+        address
+    }
+
+    async fn connect(address: &str, username: &str) -> Result<ChatClient, ClientError> {
+        let mut client = ChatClient::connect(address).await?;
+        client.set_username(Some(username.to_string()));
+        Ok(client)
+    }
+
+    async fn next(client: &mut ChatClient) -> Result<UserMessage, Box<dyn Error>> {
+        let message = timeout(Duration::from_secs(5), client.next()).await?;
+        Ok(message.ok_or("connection closed")??)
+    }
+
+    #[tokio::test]
+    async fn test_text_and_reply() -> Result<(), Box<dyn Error>> {
+        let address = start_server().await;
+        let mut alice = connect(&address, "alice").await?;
+        let mut bob = connect(&address, "bob").await?;
+
+        alice.send(Message::Text(String::from("hello"))).await?;
+        let received = next(&mut bob).await?;
+        assert_eq!(received.username.as_deref(), Some("alice"));
+        assert_eq!(received.message, Message::Text(String::from("hello")));
+        assert!(received.timestamp.is_some());
+        // the sender gets it back too, with the same id
+        let id = received.id.unwrap();
+        assert_eq!(next(&mut alice).await?.id, Some(id));
+
+        let reply = UserMessage {
+            reply_to: Some(id),
+            ..UserMessage::new(Some(String::from("bob")), Message::Text(String::from("hi")))
+        };
+        bob.send_message(&reply).await?;
+        let received = next(&mut alice).await?;
+        assert_eq!(received.reply_to, Some(id));
+        assert_eq!(received.username.as_deref(), Some("bob"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_needs_owner() -> Result<(), Box<dyn Error>> {
+        let address = start_server().await;
+        let alice = connect(&address, "alice").await?;
+        let mut bob = connect(&address, "bob").await?;
+
+        alice.send(Message::Text(String::from("mine"))).await?;
+        let id = next(&mut bob).await?.id.unwrap();
+        bob.send(Message::Text(String::from("hi"))).await?;
+        assert_eq!(next(&mut bob).await?.message, Message::Text(String::from("hi")));
+
+        // bob can't delete alice's message, even by sending her name, which doesn't match his connection
+        bob.send(Message::Delete { id }).await?;
+        bob.set_username(Some(String::from("alice")));
+        bob.send(Message::Delete { id }).await?;
+        // messages without a username are sent as the bound one
+        bob.set_username(None);
+        bob.send(Message::Text(String::from("done"))).await?;
+        let received = next(&mut bob).await?;
+        assert_eq!(received.message, Message::Text(String::from("done")));
+        assert_eq!(received.username.as_deref(), Some("bob"));
+
+        alice.send(Message::Delete { id }).await?;
+        let received = next(&mut bob).await?;
+        assert_eq!(received.message, Message::Delete { id });
+        assert_eq!(received.username.as_deref(), Some("alice"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_bytes() -> Result<(), Box<dyn Error>> {
+        let address = start_server().await;
+        let alice = connect(&address, "alice").await?;
+        let mut bob = connect(&address, "bob").await?;
+
+        // not UTF-8, and large enough to arrive in several reads
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let file = Message::File {
+            name: String::from("data.bin"),
+            mime: String::from("application/octet-stream"),
+            data,
         };
-        broadcast
-            .send((peer_address.clone(), msg.clone()))
-            .map_err(|_| ServerError::MessageSendFailed(peer_address.clone()))?;
+        alice.send(file.clone()).await?;
+        alice.send(Message::Text(String::from("sent"))).await?;
+
+        assert_eq!(next(&mut bob).await?.message, file);
+        assert_eq!(next(&mut bob).await?.message, Message::Text(String::from("sent")));
+
+        // senders don't get their own files back, just the text
+        let (_, mut messages) = alice.split();
+        let received = timeout(Duration::from_secs(5), messages.next()).await?.unwrap()?;
+        assert_eq!(received.message, Message::Text(String::from("sent")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_closes_connection() -> Result<(), Box<dyn Error>> {
+        let address = start_server().await;
+        let mut stream = TcpStream::connect(&address).await?;
+        let length = (MAX_MESSAGE_LEN + 1) as u32;
+        stream.write_all(&length.to_le_bytes()).await?;
+
+        // the server hangs up without waiting for the rest of the message
+        let mut buffer = [0u8; 1];
+        let read = timeout(Duration::from_secs(5), stream.read(&mut buffer)).await??;
+        assert_eq!(read, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connect_failed() -> Result<(), Box<dyn Error>> {
+        // nothing listens on a port that was just freed
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        drop(listener);
+        assert!(matches!(ChatClient::connect(address).await, Err(ClientError::ConnectFailed(_))));
+        Ok(())
     }
 }
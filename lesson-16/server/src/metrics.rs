@@ -0,0 +1,163 @@
+use std::convert::Infallible;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use lazy_static::lazy_static;
+use prometheus::{opts, Encoder, IntCounter, IntCounterVec, IntGauge, Registry, TextEncoder, TEXT_FORMAT};
+use tokio::net::TcpListener;
+use tracing::{event, Level};
+
+use crate::ServerError;
+
+lazy_static! {
+    pub static ref CONNECTIONS: IntCounter =
+        IntCounter::new("chat_connections_total", "Connections accepted since startup.").unwrap();
+    pub static ref CONNECTED_CLIENTS: IntGauge =
+        IntGauge::new("chat_connected_clients", "Currently connected clients.").unwrap();
+    pub static ref MESSAGES_RECEIVED: IntCounterVec = IntCounterVec::new(
+        opts!("chat_messages_received_total", "Messages received from clients, by kind."),
+        &["kind"]
+    )
+    .unwrap();
+    pub static ref BYTES_RECEIVED: IntCounter =
+        IntCounter::new("chat_bytes_received_total", "Bytes read from clients.").unwrap();
+    pub static ref BYTES_SENT: IntCounter = IntCounter::new("chat_bytes_sent_total", "Bytes written to clients.").unwrap();
+    pub static ref DB_ERRORS: IntCounter = IntCounter::new("chat_db_errors_total", "Failed DB reads and writes.").unwrap();
+    pub static ref SERVER_ERRORS: IntCounterVec = IntCounterVec::new(
+        opts!("chat_server_errors_total", "Errors handling clients, by ServerError variant."),
+        &["error"]
+    )
+    .unwrap();
+    /// Messages deleted by the retention policy, labelled by `age` or `count`.
+    pub static ref MESSAGES_PRUNED: IntCounterVec = IntCounterVec::new(
+        opts!("chat_messages_pruned_total", "Messages deleted by the retention policy, by reason."),
+        &["reason"]
+    )
+    .unwrap();
+    /// Every metric above, which is what's served at `/metrics`.
+    static ref REGISTRY: Registry = {
+        let registry = Registry::new();
+        registry.register(Box::new(CONNECTIONS.clone())).unwrap();
+        registry.register(Box::new(CONNECTED_CLIENTS.clone())).unwrap();
+        registry.register(Box::new(MESSAGES_RECEIVED.clone())).unwrap();
+        registry.register(Box::new(BYTES_RECEIVED.clone())).unwrap();
+        registry.register(Box::new(BYTES_SENT.clone())).unwrap();
+        registry.register(Box::new(DB_ERRORS.clone())).unwrap();
+        registry.register(Box::new(SERVER_ERRORS.clone())).unwrap();
+        registry.register(Box::new(MESSAGES_PRUNED.clone())).unwrap();
+        registry
+    };
+}
+
+/// Count an error in `SERVER_ERRORS`, and in `DB_ERRORS` if the DB failed.
+pub fn record_error(error: &ServerError) {
+    SERVER_ERRORS.with_label_values(&[error.name()]).inc();
+    if matches!(
+        error,
+        ServerError::DBWriteFailed | ServerError::DBReadFailed
+    ) {
+        DB_ERRORS.inc();
+    }
+}
+
+/// Render all metrics in the Prometheus text format.
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut out = Vec::new();
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut out)?;
+    // the text format is always UTF-8
+    Ok(String::from_utf8(out).unwrap())
+}
+
+/// Serve `render()` at `/metrics` over HTTP, until the server stops.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(async move {
+                    let connection = http1::Builder::new().serve_connection(TokioIo::new(stream), service_fn(respond));
+                    if let Err(e) = connection.await {
+                        event!(Level::WARN, "Failed to serve metrics: {e}");
+                    }
+                });
+            }
+            Err(e) => event!(Level::ERROR, "Failed to accept metrics connection: {e}"),
+        }
+    }
+}
+
+async fn respond(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    if request.method() != Method::GET || request.uri().path() != "/metrics" {
+        return Ok(response(StatusCode::NOT_FOUND, "text/plain; charset=utf-8", String::from("Not Found\n")));
+    }
+    Ok(match render() {
+        Ok(metrics) => response(StatusCode::OK, TEXT_FORMAT, metrics),
+        Err(e) => {
+            event!(Level::ERROR, "Failed to render metrics: {e}");
+            response(StatusCode::INTERNAL_SERVER_ERROR, "text/plain; charset=utf-8", e.to_string())
+        }
+    })
+}
+
+fn response(status: StatusCode, content_type: &'static str, body: String) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::metrics::{self, MESSAGES_PRUNED};
+    use crate::ServerError;
+
+    #[test]
+    fn test_render() -> Result<(), Box<dyn Error>> {
+        metrics::record_error(&ServerError::DBWriteFailed);
+        MESSAGES_PRUNED.with_label_values(&["age"]).inc_by(3);
+        let rendered = metrics::render()?;
+        assert!(rendered.contains("# TYPE chat_db_errors_total counter\n"));
+        assert!(rendered.contains("chat_server_errors_total{error=\"DBWriteFailed\"} "));
+        assert!(rendered.contains("chat_messages_pruned_total{reason=\"age\"} "));
+        // metrics are listed even before they're used
+        assert!(rendered.contains("# TYPE chat_connected_clients gauge\n"));
+        Ok(())
+    }
+
+    async fn get(address: std::net::SocketAddr, path: &str) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(address).await?;
+        stream
+            .write_all(format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").as_bytes())
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_serve() -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        tokio::spawn(metrics::serve(listener));
+
+        let response = get(address, "/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("content-type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("chat_connections_total "));
+
+        let response = get(address, "/").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        Ok(())
+    }
+}
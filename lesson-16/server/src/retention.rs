@@ -0,0 +1,51 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tracing::{event, Level};
+
+use crate::db;
+use crate::metrics::{self, MESSAGES_PRUNED};
+
+/// Which messages `retention_task` deletes, `None` disables a limit.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Delete messages older than this.
+    pub max_age: Option<chrono::Duration>,
+    /// Keep at most this many messages, deleting the oldest first.
+    pub max_messages: Option<i64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_messages.is_some()
+    }
+}
+
+/// Prune messages according to `policy` every `interval`, until the server stops.
+pub async fn retention_task(db: Arc<Pool<Sqlite>>, policy: RetentionPolicy, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let cutoff = policy.max_age.map(|max_age| Utc::now() - max_age);
+        match db::prune_messages(&db, cutoff, policy.max_messages).await {
+            Ok(stats) => {
+                MESSAGES_PRUNED.with_label_values(&["age"]).inc_by(stats.by_age);
+                MESSAGES_PRUNED.with_label_values(&["count"]).inc_by(stats.by_count);
+                if stats.by_age > 0 || stats.by_count > 0 {
+                    event!(
+                        Level::INFO,
+                        "Pruned {} messages by age and {} by count",
+                        stats.by_age,
+                        stats.by_count
+                    );
+                }
+            }
+            Err(e) => {
+                metrics::record_error(&e);
+                event!(Level::ERROR, "Failed to prune messages: {e}");
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::env;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::content;
+use rocket::{Request, State};
+use sqlx::SqlitePool;
+
+use crate::db;
+use crate::error::ApiError;
+use crate::files;
+use crate::Config;
+
+/// Request guard for admin routes, requires `Authorization: Bearer <token>` matching `admin_token`.
+///
+/// Without an `admin_token` the admin routes are disabled, and always return a `403`.
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ApiError;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let admin_token = request
+            .rocket()
+            .state::<Config>()
+            .and_then(|config| config.admin_token.as_deref());
+        let given = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match (admin_token, given) {
+            (Some(token), Some(given)) if token == given => Outcome::Success(Admin),
+            _ => Outcome::Error((
+                Status::Forbidden,
+                ApiError::Forbidden(String::from("Invalid admin token.")),
+            )),
+        }
+    }
+}
+
+/// List uploaded files, oldest first, optionally only those from one user.
+#[get("/api/admin/files?<username>")]
+pub async fn list_files(
+    _admin: Admin,
+    username: Option<&str>,
+    db: &State<SqlitePool>,
+) -> Result<content::RawJson<String>, ApiError> {
+    let files = db::list_files(db, username).await?;
+    // serializing plain data can't fail
+    Ok(content::RawJson(serde_json::to_string(&files).unwrap()))
+}
+
+/// Purge a single uploaded file.
+#[delete("/api/admin/files/<id>")]
+pub async fn purge_file(_admin: Admin, id: i64, db: &State<SqlitePool>) -> Result<(), ApiError> {
+    let file = db::get_file(db, id).await?;
+    files::purge_file(db, &env::current_dir()?.join("files"), &file).await?;
+    files::update_bytes_stored(db).await
+}
+
+/// Purge all files uploaded by a user, returning how many were purged.
+#[delete("/api/admin/files?<username>")]
+pub async fn purge_user_files(
+    _admin: Admin,
+    username: &str,
+    db: &State<SqlitePool>,
+) -> Result<content::RawJson<String>, ApiError> {
+    let files_path = env::current_dir()?.join("files");
+    let user_files = db::list_files(db, Some(username)).await?;
+    for file in &user_files {
+        files::purge_file(db, &files_path, file).await?;
+    }
+    files::update_bytes_stored(db).await?;
+    Ok(content::RawJson(
+        serde_json::json!({ "purged": user_files.len() }).to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use clap::Parser;
+    use rocket::http::{Header, Status};
+    use rocket::local::asynchronous::Client;
+    use serde_json::Value;
+
+    use crate::db::tests::test_db;
+    use crate::{admin, db, Config};
+
+    /// Client for a server with just the admin routes, and three uploads from two users.
+    async fn admin_client(args: &[&str]) -> Client {
+        let db = test_db().await;
+        let now = Utc::now();
+        for (name, username) in [("a.txt", "alice"), ("b.txt", "bob"), ("c.txt", "alice")] {
+            // hashes no blob is stored under, so purging only touches the DB
+            let hash = format!("test-admin-{name}");
+            db::insert_file(&db, name, &hash, &Some(username.to_string()), 10, now, None)
+                .await
+                .unwrap();
+        }
+        let rocket = rocket::build()
+            .manage(Config::parse_from([&["web"], args].concat()))
+            .manage(db)
+            .mount(
+                "/",
+                routes![admin::list_files, admin::purge_file, admin::purge_user_files],
+            );
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    fn bearer(token: &str) -> Header<'static> {
+        Header::new("Authorization", format!("Bearer {token}"))
+    }
+
+    /// Names of the files uploaded by `username`, as listed by the admin API.
+    async fn file_names(client: &Client, username: &str) -> Vec<String> {
+        let response = client
+            .get(format!("/api/admin/files?username={username}"))
+            .header(bearer("secret"))
+            .dispatch()
+            .await;
+        let files: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        files
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|file| file["name"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[rocket::async_test]
+    async fn test_admin_requires_token() {
+        let client = admin_client(&["--admin-token", "secret"]).await;
+        let response = client.get("/api/admin/files").dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.get("/api/admin/files").header(bearer("guess")).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client.get("/api/admin/files").header(bearer("secret")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+
+        // without a token configured, no token is accepted
+        let client = admin_client(&[]).await;
+        let response = client.get("/api/admin/files").header(bearer("")).dispatch().await;
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[rocket::async_test]
+    async fn test_admin_purges_files() {
+        let client = admin_client(&["--admin-token", "secret"]).await;
+        assert_eq!(file_names(&client, "alice").await, vec!["a.txt", "c.txt"]);
+
+        let response = client
+            .delete("/api/admin/files?username=alice")
+            .header(bearer("secret"))
+            .dispatch()
+            .await;
+        let purged: Value = serde_json::from_str(&response.into_string().await.unwrap()).unwrap();
+        assert_eq!(purged["purged"], 2);
+        assert!(file_names(&client, "alice").await.is_empty());
+
+        assert_eq!(file_names(&client, "bob").await, vec!["b.txt"]);
+        let response = client.delete("/api/admin/files/2").header(bearer("secret")).dispatch().await;
+        assert_eq!(response.status(), Status::Ok);
+        assert!(file_names(&client, "bob").await.is_empty());
+        let response = client.delete("/api/admin/files/2").header(bearer("secret")).dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+    }
+}
@@ -0,0 +1,790 @@
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::sqlite::SqliteRow;
+use sqlx::{Row, SqlitePool};
+
+use crate::error::ApiError;
+use crate::images;
+use crate::message::UserMessage;
+
+/// Create the database tables, and add any columns missing from databases created by older versions.
+pub async fn setup(db: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS messages \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        username VARCHAR(250), \
+        message VARCHAR(250) NOT NULL, \
+        kind VARCHAR(16) NOT NULL DEFAULT 'Text', \
+        timestamp TEXT, \
+        reply_to INTEGER, \
+        file_id INTEGER\
+    );",
+    )
+    .execute(db)
+    .await?;
+
+    add_column_if_missing(db, "messages", "kind", "VARCHAR(16) NOT NULL DEFAULT 'Text'").await?;
+    add_column_if_missing(db, "messages", "timestamp", "TEXT").await?;
+    add_column_if_missing(db, "messages", "reply_to", "INTEGER").await?;
+    add_column_if_missing(db, "messages", "file_id", "INTEGER").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS files \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        name VARCHAR(250) NOT NULL, \
+        hash VARCHAR(64), \
+        username VARCHAR(250), \
+        size INTEGER NOT NULL, \
+        uploaded_at TEXT NOT NULL, \
+        width INTEGER, \
+        height INTEGER\
+    );",
+    )
+    .execute(db)
+    .await?;
+    add_column_if_missing(db, "files", "hash", "VARCHAR(64)").await?;
+    add_column_if_missing(db, "files", "width", "INTEGER").await?;
+    add_column_if_missing(db, "files", "height", "INTEGER").await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS message_edits \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        message_id INTEGER NOT NULL, \
+        old_message VARCHAR(250) NOT NULL, \
+        edited_at TEXT NOT NULL\
+    );",
+    )
+    .execute(db)
+    .await?;
+
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS reactions \
+    (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, \
+        message_id INTEGER NOT NULL, \
+        username VARCHAR(250), \
+        emoji VARCHAR(32) NOT NULL, \
+        UNIQUE (message_id, username, emoji)\
+    );",
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// A message as stored in the DB, returned by the history API.
+#[derive(Serialize, Debug)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub timestamp: Option<DateTime<Utc>>,
+    pub username: Option<String>,
+    pub kind: String,
+    pub message: String,
+    pub reply_to: Option<i64>,
+    /// Id of the uploaded file, for `File` and `Photo` messages.
+    pub file_id: Option<i64>,
+    /// Number of reactions for each emoji.
+    pub reactions: BTreeMap<String, i64>,
+}
+
+/// SQLite has no `ADD COLUMN IF NOT EXISTS`, so check the table info first.
+async fn add_column_if_missing(
+    db: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<(), sqlx::Error> {
+    let columns = sqlx::query(&format!("PRAGMA table_info({table})"))
+        .fetch_all(db)
+        .await?;
+    let exists = columns
+        .iter()
+        .any(|row| row.get::<String, &str>("name") == column);
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {table} ADD COLUMN {column} {definition}"))
+            .execute(db)
+            .await?;
+    }
+    Ok(())
+}
+
+/// A file uploaded through `POST /api/files`.
+#[derive(Serialize, Debug)]
+pub struct StoredFile {
+    pub id: i64,
+    /// The original, sanitized, name of the file.
+    pub name: String,
+    /// SHA-256 of the contents, which the file is stored under.
+    pub hash: Option<String>,
+    pub username: Option<String>,
+    pub size: i64,
+    pub uploaded_at: DateTime<Utc>,
+    /// Dimensions in pixels, only set for images.
+    pub width: Option<i64>,
+    pub height: Option<i64>,
+}
+
+impl StoredFile {
+    /// Name of the file in the `files` directory.
+    pub fn blob_name(&self) -> String {
+        // files uploaded before content addressing was added are stored under their id
+        self.hash.clone().unwrap_or(self.id.to_string())
+    }
+
+    /// Name of the thumbnail in the `files` directory, if this is an image.
+    pub fn thumbnail_name(&self) -> Option<String> {
+        self.width.map(|_| images::thumbnail_name(&self.blob_name()))
+    }
+}
+
+/// Record an uploaded file, returning the id assigned to it.
+pub async fn insert_file(
+    db: &SqlitePool,
+    name: &str,
+    hash: &str,
+    username: &Option<String>,
+    size: i64,
+    uploaded_at: DateTime<Utc>,
+    dimensions: Option<(u32, u32)>,
+) -> Result<i64, ApiError> {
+    let result = sqlx::query(
+        "INSERT INTO files (name, hash, username, size, uploaded_at, width, height) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+    )
+    .bind(name)
+    .bind(hash)
+    .bind(username)
+    .bind(size)
+    .bind(uploaded_at)
+    .bind(dimensions.map(|(width, _)| width))
+    .bind(dimensions.map(|(_, height)| height))
+    .execute(db)
+    .await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Number of messages removed by `prune_messages`, for each part of the retention policy.
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub by_age: u64,
+    pub by_count: u64,
+}
+
+/// Delete messages older than `cutoff`, then the oldest messages beyond `max_messages`,
+/// along with their edit history and reactions.
+///
+/// Messages stored before timestamps were recorded are only pruned by count.
+pub async fn prune_messages(
+    db: &SqlitePool,
+    cutoff: Option<DateTime<Utc>>,
+    max_messages: Option<i64>,
+) -> Result<PruneStats, ApiError> {
+    let mut stats = PruneStats::default();
+    let mut tx = db.begin().await?;
+    if let Some(cutoff) = cutoff {
+        stats.by_age = sqlx::query("DELETE FROM messages WHERE timestamp < $1")
+            .bind(cutoff)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+    }
+    if let Some(max_messages) = max_messages {
+        stats.by_count = sqlx::query(
+            "DELETE FROM messages WHERE id NOT IN \
+            (SELECT id FROM messages ORDER BY id DESC LIMIT $1)",
+        )
+        .bind(max_messages)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    }
+    sqlx::query("DELETE FROM message_edits WHERE message_id NOT IN (SELECT id FROM messages)")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM reactions WHERE message_id NOT IN (SELECT id FROM messages)")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(stats)
+}
+
+const FILE_COLUMNS: &str = "id, name, hash, username, size, uploaded_at, width, height";
+
+pub async fn get_file(db: &SqlitePool, id: i64) -> Result<StoredFile, ApiError> {
+    let row = sqlx::query(&format!("SELECT {FILE_COLUMNS} FROM files WHERE id=$1"))
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound(format!("File {id}")))?;
+    Ok(stored_file(&row)?)
+}
+
+/// List uploaded files, oldest first, optionally only those from one user.
+pub async fn list_files(
+    db: &SqlitePool,
+    username: Option<&str>,
+) -> Result<Vec<StoredFile>, ApiError> {
+    let rows = match username {
+        Some(username) => {
+            sqlx::query(&format!(
+                "SELECT {FILE_COLUMNS} FROM files WHERE username=$1 ORDER BY id"
+            ))
+            .bind(username)
+            .fetch_all(db)
+            .await?
+        }
+        None => {
+            sqlx::query(&format!("SELECT {FILE_COLUMNS} FROM files ORDER BY id"))
+                .fetch_all(db)
+                .await?
+        }
+    };
+    Ok(rows
+        .iter()
+        .map(stored_file)
+        .collect::<Result<Vec<_>, sqlx::Error>>()?)
+}
+
+/// List files uploaded before `cutoff`, oldest first.
+pub async fn files_uploaded_before(
+    db: &SqlitePool,
+    cutoff: DateTime<Utc>,
+) -> Result<Vec<StoredFile>, ApiError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {FILE_COLUMNS} FROM files WHERE uploaded_at < $1 ORDER BY id"
+    ))
+    .bind(cutoff)
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .iter()
+        .map(stored_file)
+        .collect::<Result<Vec<_>, sqlx::Error>>()?)
+}
+
+pub async fn delete_file(db: &SqlitePool, id: i64) -> Result<(), ApiError> {
+    sqlx::query("DELETE FROM files WHERE id=$1")
+        .bind(id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Whether any upload is still stored under `hash`.
+pub async fn hash_in_use(db: &SqlitePool, hash: &str) -> Result<bool, ApiError> {
+    let row = sqlx::query("SELECT COUNT(*) AS count FROM files WHERE hash=$1")
+        .bind(hash)
+        .fetch_one(db)
+        .await?;
+    Ok(row.try_get::<i64, &str>("count")? > 0)
+}
+
+/// Bytes used on disk by all uploads, counting identical uploads once.
+pub async fn stored_bytes(db: &SqlitePool) -> Result<i64, ApiError> {
+    // uploads from before content addressing have no hash, and are stored separately
+    let row = sqlx::query(
+        "SELECT COALESCE(SUM(size), 0) AS total FROM \
+        (SELECT MAX(size) AS size FROM files GROUP BY COALESCE(hash, id))",
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(row.try_get("total")?)
+}
+
+fn stored_file(row: &SqliteRow) -> Result<StoredFile, sqlx::Error> {
+    Ok(StoredFile {
+        id: row.try_get("id")?,
+        name: row.try_get("name")?,
+        hash: row.try_get("hash")?,
+        username: row.try_get("username")?,
+        size: row.try_get("size")?,
+        uploaded_at: row.try_get("uploaded_at")?,
+        width: row.try_get("width")?,
+        height: row.try_get("height")?,
+    })
+}
+
+/// Store a message, returning the id assigned to it.
+///
+/// Replies are only stored if the message they reply to exists.
+pub async fn insert_message(
+    db: &SqlitePool,
+    message: &UserMessage,
+    content: &str,
+    file_id: Option<i64>,
+) -> Result<i64, ApiError> {
+    if let Some(parent) = message.reply_to {
+        sqlx::query("SELECT id FROM messages WHERE id=$1")
+            .bind(parent)
+            .fetch_optional(db)
+            .await?
+            .ok_or(ApiError::NotFound(format!("Message {parent}")))?;
+    }
+    let result = sqlx::query(
+        "INSERT INTO messages (username, message, kind, timestamp, reply_to, file_id) \
+        VALUES ($1, $2, $3, $4, $5, $6)",
+    )
+    .bind(&message.username)
+    .bind(content)
+    .bind(message.message.kind())
+    .bind(message.timestamp)
+    .bind(message.reply_to)
+    .bind(file_id)
+    .execute(db)
+    .await?;
+    Ok(result.last_insert_rowid())
+}
+
+/// Check that a message exists, and that it was sent by `username`, returning the message kind.
+///
+/// Anonymous messages have no owner, so they can never be modified.
+async fn check_owner(
+    db: &SqlitePool,
+    id: i64,
+    username: &Option<String>,
+) -> Result<String, ApiError> {
+    let row = sqlx::query("SELECT username, kind FROM messages WHERE id=$1")
+        .bind(id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound(format!("Message {id}")))?;
+    let owner: Option<String> = row.try_get("username")?;
+    if owner.is_none() || &owner != username {
+        return Err(ApiError::Forbidden(format!(
+            "Message {id} belongs to another user."
+        )));
+    }
+    Ok(row.try_get("kind")?)
+}
+
+/// Replace the text of a message, keeping the old text in `message_edits`.
+pub async fn edit_message(
+    db: &SqlitePool,
+    id: i64,
+    username: &Option<String>,
+    new_text: &str,
+) -> Result<(), ApiError> {
+    if check_owner(db, id, username).await? != "Text" {
+        return Err(ApiError::Validation(format!(
+            "Message {id} is not a text message, and can't be edited."
+        )));
+    }
+
+    let mut tx = db.begin().await?;
+    sqlx::query(
+        "INSERT INTO message_edits (message_id, old_message, edited_at) \
+        SELECT id, message, $2 FROM messages WHERE id=$1",
+    )
+    .bind(id)
+    .bind(Utc::now())
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("UPDATE messages SET message=$2 WHERE id=$1")
+        .bind(id)
+        .bind(new_text)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Delete a message along with its edit history and reactions.
+pub async fn delete_message(
+    db: &SqlitePool,
+    id: i64,
+    username: &Option<String>,
+) -> Result<(), ApiError> {
+    check_owner(db, id, username).await?;
+
+    let mut tx = db.begin().await?;
+    sqlx::query("DELETE FROM message_edits WHERE message_id=$1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM reactions WHERE message_id=$1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM messages WHERE id=$1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Add a reaction to a message, returning `false` if the user had already reacted with that emoji.
+///
+/// Anonymous users can't react, the unique constraint treats every NULL username as different, so they could
+/// react with the same emoji any number of times.
+pub async fn add_reaction(
+    db: &SqlitePool,
+    message_id: i64,
+    username: &Option<String>,
+    emoji: &str,
+) -> Result<bool, ApiError> {
+    if username.is_none() {
+        return Err(ApiError::Forbidden("Anonymous users can't react.".to_string()));
+    }
+    // a single emoji, including skin tones, flags and sequences joined into one, but nothing else
+    if emojis::get(emoji).is_none() {
+        return Err(ApiError::Validation(format!(
+            "\"{emoji}\" is not a valid reaction."
+        )));
+    }
+    sqlx::query("SELECT id FROM messages WHERE id=$1")
+        .bind(message_id)
+        .fetch_optional(db)
+        .await?
+        .ok_or(ApiError::NotFound(format!("Message {message_id}")))?;
+
+    let result = sqlx::query(
+        "INSERT OR IGNORE INTO reactions (message_id, username, emoji) VALUES ($1, $2, $3)",
+    )
+    .bind(message_id)
+    .bind(username)
+    .bind(emoji)
+    .execute(db)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Count the messages sent by other users after `last_read`, or all of them if `last_read` is `None`.
+pub async fn unread_count(
+    db: &SqlitePool,
+    username: &str,
+    last_read: Option<i64>,
+) -> Result<i64, ApiError> {
+    let row = sqlx::query(
+        "SELECT COUNT(*) AS count FROM messages \
+        WHERE id > $1 AND (username IS NULL OR username != $2)",
+    )
+    .bind(last_read.unwrap_or(0))
+    .bind(username)
+    .fetch_one(db)
+    .await?;
+    Ok(row.try_get("count")?)
+}
+
+/// Get up to `limit` messages older than `before`, oldest first, with their reaction counts.
+pub async fn history(
+    db: &SqlitePool,
+    before: Option<i64>,
+    limit: i64,
+) -> Result<Vec<StoredMessage>, ApiError> {
+    let rows = sqlx::query(
+        "SELECT id, timestamp, username, kind, message, reply_to, file_id FROM messages \
+        WHERE id < $1 ORDER BY id DESC LIMIT $2",
+    )
+    .bind(before.unwrap_or(i64::MAX))
+    .bind(limit)
+    .fetch_all(db)
+    .await?;
+
+    let mut messages = rows
+        .iter()
+        .rev()
+        .map(stored_message)
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    add_reaction_counts(db, &mut messages).await?;
+    Ok(messages)
+}
+
+/// Get a message and all replies to it, including replies to replies, oldest first.
+pub async fn thread(db: &SqlitePool, id: i64) -> Result<Vec<StoredMessage>, ApiError> {
+    let rows = sqlx::query(
+        "WITH RECURSIVE thread(id) AS (\
+            SELECT id FROM messages WHERE id=$1 \
+            UNION SELECT messages.id FROM messages JOIN thread ON messages.reply_to = thread.id\
+        ) \
+        SELECT id, timestamp, username, kind, message, reply_to, file_id FROM messages \
+        WHERE id IN thread ORDER BY id",
+    )
+    .bind(id)
+    .fetch_all(db)
+    .await?;
+    if rows.is_empty() {
+        return Err(ApiError::NotFound(format!("Message {id}")));
+    }
+
+    let mut messages = rows
+        .iter()
+        .map(stored_message)
+        .collect::<Result<Vec<_>, sqlx::Error>>()?;
+    add_reaction_counts(db, &mut messages).await?;
+    Ok(messages)
+}
+
+fn stored_message(row: &SqliteRow) -> Result<StoredMessage, sqlx::Error> {
+    Ok(StoredMessage {
+        id: row.try_get("id")?,
+        timestamp: row.try_get("timestamp")?,
+        username: row.try_get("username")?,
+        kind: row.try_get("kind")?,
+        message: row.try_get("message")?,
+        reply_to: row.try_get("reply_to")?,
+        file_id: row.try_get("file_id")?,
+        reactions: BTreeMap::new(),
+    })
+}
+
+/// Fill in the reaction counts for a list of messages sorted by id.
+async fn add_reaction_counts(
+    db: &SqlitePool,
+    messages: &mut [StoredMessage],
+) -> Result<(), ApiError> {
+    let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+        return Ok(());
+    };
+    let counts = sqlx::query(
+        "SELECT message_id, emoji, COUNT(*) AS count FROM reactions \
+        WHERE message_id BETWEEN $1 AND $2 GROUP BY message_id, emoji",
+    )
+    .bind(first.id)
+    .bind(last.id)
+    .fetch_all(db)
+    .await?;
+    for row in counts {
+        let message_id: i64 = row.try_get("message_id")?;
+        if let Some(message) = messages.iter_mut().find(|m| m.id == message_id) {
+            message
+                .reactions
+                .insert(row.try_get("emoji")?, row.try_get("count")?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use chrono::{Duration, Utc};
+    use sqlx::sqlite::SqlitePoolOptions;
+    use sqlx::SqlitePool;
+
+    use crate::db;
+    use crate::error::ApiError;
+    use crate::message::{Message, UserMessage};
+
+    pub(crate) async fn test_db() -> SqlitePool {
+        // every connection to an in-memory DB gets a new database, so only allow one
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        db::setup(&db).await.unwrap();
+        db
+    }
+
+    /// Store a text message from `username`, returning its id.
+    pub(crate) async fn send(
+        db: &SqlitePool,
+        username: Option<&str>,
+        text: &str,
+        reply_to: Option<i64>,
+    ) -> Result<i64, ApiError> {
+        let message = UserMessage {
+            id: None,
+            timestamp: Some(Utc::now()),
+            username: username.map(String::from),
+            message: Message::Text(text.to_string()),
+            reply_to,
+        };
+        db::insert_message(db, &message, text, None).await
+    }
+
+    #[tokio::test]
+    async fn test_reactions_are_counted_once_per_user() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let alice = Some(String::from("alice"));
+        let bob = Some(String::from("bob"));
+        let id = send(&db, Some("alice"), "hello", None).await?;
+
+        assert!(db::add_reaction(&db, id, &alice, "👍").await?);
+        assert!(!db::add_reaction(&db, id, &alice, "👍").await?);
+        assert!(db::add_reaction(&db, id, &bob, "👍").await?);
+        assert!(db::add_reaction(&db, id, &bob, "👍🏽").await?);
+        assert!(db::add_reaction(&db, id, &bob, "🏳️‍🌈").await?);
+
+        let messages = db::history(&db, None, 10).await?;
+        assert_eq!(messages[0].reactions.get("👍"), Some(&2));
+        assert_eq!(messages[0].reactions.get("👍🏽"), Some(&1));
+        assert_eq!(messages[0].reactions.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reactions_are_rejected() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let alice = Some(String::from("alice"));
+        let id = send(&db, Some("alice"), "hello", None).await?;
+
+        for emoji in ["", "a", "👍👍", "👍 ", "<b>"] {
+            assert!(matches!(
+                db::add_reaction(&db, id, &alice, emoji).await,
+                Err(ApiError::Validation(_))
+            ));
+        }
+        assert!(matches!(
+            db::add_reaction(&db, id, &None, "👍").await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            db::add_reaction(&db, id + 1, &alice, "👍").await,
+            Err(ApiError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_thread_includes_nested_replies() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let root = send(&db, Some("alice"), "question", None).await?;
+        let other = send(&db, Some("carol"), "unrelated", None).await?;
+        let reply = send(&db, Some("bob"), "answer", Some(root)).await?;
+        let nested = send(&db, Some("alice"), "thanks", Some(reply)).await?;
+
+        let thread = db::thread(&db, root).await?;
+        let ids: Vec<i64> = thread.iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![root, reply, nested]);
+        assert_eq!(thread[2].reply_to, Some(reply));
+        assert_eq!(db::thread(&db, other).await?.len(), 1);
+
+        assert!(matches!(db::thread(&db, 100).await, Err(ApiError::NotFound(_))));
+        assert!(matches!(
+            send(&db, Some("bob"), "lost", Some(100)).await,
+            Err(ApiError::NotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_only_owners_edit_and_delete() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let alice = Some(String::from("alice"));
+        let bob = Some(String::from("bob"));
+        let id = send(&db, Some("alice"), "helo", None).await?;
+        let anonymous = send(&db, None, "boo", None).await?;
+
+        assert!(matches!(
+            db::edit_message(&db, id, &bob, "hijacked").await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            db::edit_message(&db, anonymous, &None, "still boo").await,
+            Err(ApiError::Forbidden(_))
+        ));
+        assert!(matches!(
+            db::edit_message(&db, id + 10, &alice, "lost").await,
+            Err(ApiError::NotFound(_))
+        ));
+        db::edit_message(&db, id, &alice, "hello").await?;
+        assert_eq!(db::history(&db, None, 10).await?[0].message, "hello");
+
+        db::add_reaction(&db, id, &bob, "👍").await?;
+        assert!(matches!(
+            db::delete_message(&db, id, &bob).await,
+            Err(ApiError::Forbidden(_))
+        ));
+        db::delete_message(&db, id, &alice).await?;
+        let remaining = db::history(&db, None, 10).await?;
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].id, anonymous);
+
+        // the edit history and the reaction went with the message
+        let edits: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM message_edits")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(edits, 0);
+        let reactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reactions")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(reactions, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_history_pages_back() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let mut ids = Vec::new();
+        for text in ["one", "two", "three", "four"] {
+            ids.push(send(&db, Some("alice"), text, None).await?);
+        }
+
+        let latest = db::history(&db, None, 2).await?;
+        assert_eq!(latest.iter().map(|m| m.id).collect::<Vec<_>>(), ids[2..]);
+        let older = db::history(&db, Some(latest[0].id), 2).await?;
+        assert_eq!(older.iter().map(|m| m.id).collect::<Vec<_>>(), ids[..2]);
+        assert!(db::history(&db, Some(ids[0]), 2).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unread_count_skips_own_messages() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let first = send(&db, Some("bob"), "hi", None).await?;
+        send(&db, Some("alice"), "hey", None).await?;
+        send(&db, None, "psst", None).await?;
+        send(&db, Some("bob"), "how are you?", None).await?;
+
+        assert_eq!(db::unread_count(&db, "alice", None).await?, 3);
+        assert_eq!(db::unread_count(&db, "alice", Some(first)).await?, 2);
+        assert_eq!(db::unread_count(&db, "bob", Some(first)).await?, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stored_bytes() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let alice = Some(String::from("alice"));
+        let now = Utc::now();
+        db::insert_file(&db, "a.txt", "aaaa", &alice, 100, now, None).await?;
+        db::insert_file(&db, "b.txt", "bbbb", &alice, 50, now, None).await?;
+        db::insert_file(&db, "c.txt", "cccc", &None, 25, now, None).await?;
+        db::insert_file(&db, "d.txt", "dddd", &None, 5, now, None).await?;
+
+        assert_eq!(db::stored_bytes(&db).await?, 180);
+
+        // identical uploads are stored once
+        db::insert_file(&db, "copy.txt", "aaaa", &None, 100, now, None).await?;
+        assert_eq!(db::stored_bytes(&db).await?, 180);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prune_messages() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let bob = Some(String::from("bob"));
+        let old = UserMessage {
+            id: None,
+            timestamp: Some(Utc::now() - Duration::days(10)),
+            username: Some(String::from("alice")),
+            message: Message::Text(String::from("old")),
+            reply_to: None,
+        };
+        let old = db::insert_message(&db, &old, "old", None).await?;
+        db::add_reaction(&db, old, &bob, "👍").await?;
+        for text in ["one", "two", "three"] {
+            send(&db, Some("alice"), text, None).await?;
+        }
+
+        let stats = db::prune_messages(&db, Some(Utc::now() - Duration::days(7)), Some(2)).await?;
+        assert_eq!((stats.by_age, stats.by_count), (1, 1));
+        let texts: Vec<String> = db::history(&db, None, 10)
+            .await?
+            .into_iter()
+            .map(|message| message.message)
+            .collect();
+        assert_eq!(texts, vec!["two", "three"]);
+        let reactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM reactions")
+            .fetch_one(&db)
+            .await?;
+        assert_eq!(reactions, 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+use std::io;
+
+use rocket::http::Status;
+use rocket::response::{self, content, Responder};
+use rocket::Request;
+use serde_json::json;
+use thiserror::Error;
+use tracing::{event, Level};
+
+/// Custom API errors, returned from route handlers and rendered as JSON.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid image: {0}")]
+    Image(#[from] image::ImageError),
+    #[error("Invalid request: {0}")]
+    Validation(String),
+    #[error("{0} not found.")]
+    NotFound(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
+    #[error("Storage quota exceeded: {0}")]
+    QuotaExceeded(String),
+}
+
+impl ApiError {
+    /// The HTTP status code this error maps to.
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::Database(sqlx::Error::RowNotFound) => Status::NotFound,
+            ApiError::Database(_) => Status::InternalServerError,
+            ApiError::Io(e) if e.kind() == io::ErrorKind::NotFound => Status::NotFound,
+            ApiError::Io(_) => Status::InternalServerError,
+            ApiError::Image(image::ImageError::IoError(_)) => Status::InternalServerError,
+            ApiError::Image(_) => Status::UnprocessableEntity,
+            ApiError::Validation(_) => Status::UnprocessableEntity,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::QuotaExceeded(_) => Status::PayloadTooLarge,
+        }
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        if status.class().is_server_error() {
+            event!(Level::ERROR, "{} {}: {self}", request.method(), request.uri());
+        } else {
+            event!(Level::INFO, "{} {}: {self}", request.method(), request.uri());
+        }
+        // don't leak internal details (SQL, paths) to the client on server errors
+        let message = if status.class().is_server_error() {
+            status.reason_lossy().to_string()
+        } else {
+            self.to_string()
+        };
+        (status, error_body(status, &message)).respond_to(request)
+    }
+}
+
+/// Build the JSON body shared by `ApiError` and the catchers.
+pub fn error_body(status: Status, message: &str) -> content::RawJson<String> {
+    content::RawJson(
+        json!({
+            "status": status.code,
+            "error": status.reason_lossy(),
+            "message": message,
+        })
+        .to_string(),
+    )
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request) -> content::RawJson<String> {
+    error_body(
+        Status::NotFound,
+        &format!("{} was not found.", request.uri().path()),
+    )
+}
+
+#[catch(403)]
+pub fn forbidden(_request: &Request) -> content::RawJson<String> {
+    error_body(
+        Status::Forbidden,
+        "A valid admin token is required, as `Authorization: Bearer <token>`.",
+    )
+}
+
+#[catch(413)]
+pub fn payload_too_large(_request: &Request) -> content::RawJson<String> {
+    error_body(Status::PayloadTooLarge, "The uploaded file is too large.")
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(_request: &Request) -> content::RawJson<String> {
+    error_body(
+        Status::UnprocessableEntity,
+        "The request was well-formed but could not be processed.",
+    )
+}
+
+#[catch(500)]
+pub fn internal_error(_request: &Request) -> content::RawJson<String> {
+    error_body(Status::InternalServerError, "Internal Server Error")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use rocket::http::Status;
+
+    use crate::error::ApiError;
+
+    #[test]
+    fn test_error_status() {
+        assert_eq!(
+            ApiError::Database(sqlx::Error::RowNotFound).status(),
+            Status::NotFound
+        );
+        assert_eq!(
+            ApiError::Database(sqlx::Error::PoolClosed).status(),
+            Status::InternalServerError
+        );
+        assert_eq!(
+            ApiError::Io(io::Error::from(io::ErrorKind::PermissionDenied)).status(),
+            Status::InternalServerError
+        );
+        assert_eq!(
+            ApiError::Validation(String::from("bad")).status(),
+            Status::UnprocessableEntity
+        );
+        assert_eq!(
+            ApiError::QuotaExceeded(String::from("full")).status(),
+            Status::PayloadTooLarge
+        );
+    }
+}
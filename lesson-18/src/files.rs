@@ -0,0 +1,385 @@
+use std::env;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use prometheus::{opts, register_gauge, Gauge};
+use rocket::form::Form;
+use rocket::fs::{NamedFile, TempFile};
+use rocket::http::{ContentType, Header};
+use rocket::response::content;
+use rocket::State;
+use rust_chat::storage::sanitize_filename;
+use sha2::{Digest, Sha256};
+use sqlx::SqlitePool;
+use tokio::io::AsyncReadExt;
+use tracing::{event, Level};
+
+use crate::db;
+use crate::error::ApiError;
+use crate::images;
+use crate::Config;
+
+/// Used to give concurrent uploads distinct temporary files.
+static UPLOAD_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+const MIB: i64 = 1024 * 1024;
+
+lazy_static! {
+    pub static ref BYTES_STORED_GAUGE: Gauge = register_gauge!(opts!("files_bytes_stored", "The number of bytes used by uploaded files.")).unwrap();
+}
+
+/// When uploads are deleted by `retention_task`, `None` disables a limit.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Delete uploads older than this.
+    pub max_age: Option<chrono::Duration>,
+    /// Delete the oldest uploads until at most this many bytes are stored.
+    pub max_bytes: Option<i64>,
+}
+
+/// Multipart form for `POST /api/files`.
+#[derive(FromForm)]
+pub struct Upload<'r> {
+    file: TempFile<'r>,
+    username: Option<String>,
+}
+
+/// Types downloads may be shown inline as, anything else is always downloaded, so uploaded HTML or SVG can't run
+/// scripts on this origin.
+const INLINE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp", "image/avif"];
+
+/// Bytes read from the start of a download to detect its type.
+const SNIFF_LEN: u64 = 512;
+
+/// A stored file, sent with its original name.
+#[derive(Responder)]
+pub struct Download {
+    inner: NamedFile,
+    content_type: ContentType,
+    disposition: Header<'static>,
+    nosniff: Header<'static>,
+}
+
+/// A `Content-Disposition` of `kind`, `inline` or `attachment`, for a file called `name`.
+///
+/// `filename` only takes ASCII, so other characters are replaced there, and the full name is given in `filename*`,
+/// see RFC 6266.
+fn content_disposition(kind: &str, name: &str) -> String {
+    // names are sanitized on upload, so they can't contain quotes
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() { c } else { '_' })
+        .collect();
+    let mut encoded = String::new();
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    format!("{kind}; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")
+}
+
+/// Hash a file with SHA-256, returning the hex digest.
+async fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// A unique path in `files_path` to move an upload to while it is processed.
+fn temp_path(files_path: &Path) -> PathBuf {
+    files_path.join(format!(
+        ".upload-{}-{}",
+        std::process::id(),
+        UPLOAD_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Move a processed upload into content addressed storage, returning the hash it is stored under.
+///
+/// Blobs are named after the SHA-256 of their contents, so names never collide,
+/// and identical uploads share a single blob.
+async fn store_blob(temp_path: &Path, files_path: &Path) -> io::Result<String> {
+    let hash = hash_file(temp_path).await?;
+    let blob_path = files_path.join(&hash);
+    if tokio::fs::try_exists(&blob_path).await? {
+        tokio::fs::remove_file(temp_path).await?;
+    } else {
+        tokio::fs::rename(temp_path, &blob_path).await?;
+    }
+    Ok(hash)
+}
+
+/// Strip metadata from images and store the upload, returning its hash and the processed image.
+async fn store_upload(
+    file: &mut TempFile<'_>,
+    files_path: &Path,
+) -> Result<(String, Option<images::ProcessedImage>), ApiError> {
+    let temp_path = temp_path(files_path);
+    file.move_copy_to(&temp_path).await?;
+
+    let path = temp_path.clone();
+    // decoding and encoding images is CPU heavy, keep it off the async workers
+    let result = match tokio::task::spawn_blocking(move || images::process_image(&path)).await {
+        Ok(Ok(image)) => store_blob(&temp_path, files_path)
+            .await
+            .map(|hash| (hash, image))
+            .map_err(ApiError::from),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(io::Error::other(e).into()),
+    };
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+/// Reject an upload of `size` bytes if it would exceed the storage quota.
+///
+/// The quota is global, uploads come with whatever username the page sends, so there's no user to charge them to.
+async fn check_quota(db: &SqlitePool, size: i64, config: &Config) -> Result<(), ApiError> {
+    let global_quota = config.storage_quota_mb as i64 * MIB;
+    if global_quota > 0 && db::stored_bytes(db).await? + size > global_quota {
+        return Err(ApiError::QuotaExceeded(String::from(
+            "The server has no space left for uploads.",
+        )));
+    }
+    Ok(())
+}
+
+/// Set `BYTES_STORED_GAUGE` from the DB.
+pub async fn update_bytes_stored(db: &SqlitePool) -> Result<(), ApiError> {
+    BYTES_STORED_GAUGE.set(db::stored_bytes(db).await? as f64);
+    Ok(())
+}
+
+/// Delete an upload, and its blob and thumbnail once no other upload shares them.
+///
+/// Messages referencing the file are kept, downloading it will return a `404`.
+pub async fn purge_file(
+    db: &SqlitePool,
+    files_path: &Path,
+    file: &db::StoredFile,
+) -> Result<(), ApiError> {
+    db::delete_file(db, file.id).await?;
+    if let Some(hash) = &file.hash {
+        if db::hash_in_use(db, hash).await? {
+            return Ok(());
+        }
+    }
+    let mut paths = vec![files_path.join(file.blob_name())];
+    paths.extend(file.thumbnail_name().map(|name| files_path.join(name)));
+    for path in paths {
+        match tokio::fs::remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    event!(Level::INFO, "Purged upload {}: {}", file.id, file.name);
+    Ok(())
+}
+
+/// Purge uploads that are too old, then the oldest uploads until the total size is within the policy.
+///
+/// Returns the number of uploads purged.
+pub async fn enforce_retention(
+    db: &SqlitePool,
+    files_path: &Path,
+    policy: &RetentionPolicy,
+) -> Result<usize, ApiError> {
+    let mut purged = 0;
+    if let Some(max_age) = policy.max_age {
+        for file in db::files_uploaded_before(db, Utc::now() - max_age).await? {
+            purge_file(db, files_path, &file).await?;
+            purged += 1;
+        }
+    }
+    if let Some(max_bytes) = policy.max_bytes {
+        for file in db::list_files(db, None).await? {
+            if db::stored_bytes(db).await? <= max_bytes {
+                break;
+            }
+            purge_file(db, files_path, &file).await?;
+            purged += 1;
+        }
+    }
+    update_bytes_stored(db).await?;
+    Ok(purged)
+}
+
+/// Enforce the retention policy every `interval`, until the server stops.
+pub async fn retention_task(
+    db: SqlitePool,
+    files_path: PathBuf,
+    policy: RetentionPolicy,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match enforce_retention(&db, &files_path, &policy).await {
+            Ok(0) => {}
+            Ok(purged) => event!(Level::INFO, "Retention purged {purged} uploads"),
+            Err(e) => event!(Level::ERROR, "Failed to enforce upload retention: {e}"),
+        }
+    }
+}
+
+/// Upload a file, returning the id to reference it by in `File` and `Photo` messages.
+///
+/// The size of uploads is limited by the `file` and `data-form` limits set in `rocket()`.
+/// The original name is kept in the DB, the file itself is stored under the hash of its contents.
+/// Images have their metadata stripped, and get a thumbnail, see `images::process_image`.
+/// Uploads that would exceed `--storage-quota-mb` are rejected with a `413`.
+#[post("/api/files", data = "<upload>")]
+pub async fn upload(
+    mut upload: Form<Upload<'_>>,
+    db: &State<SqlitePool>,
+    config: &State<Config>,
+) -> Result<content::RawJson<String>, ApiError> {
+    let name = upload
+        .file
+        .raw_name()
+        .map(|name| sanitize_filename(name.dangerous_unsafe_unsanitized_raw().as_str()))
+        .ok_or(ApiError::Validation(String::from(
+            "Uploaded files must have a name.",
+        )))?;
+
+    check_quota(db, upload.file.len() as i64, config).await?;
+
+    let files_path = env::current_dir()?.join("files");
+    let (hash, image) = store_upload(&mut upload.file, &files_path).await?;
+    // stripping metadata changes the size of images
+    let size = tokio::fs::metadata(files_path.join(&hash)).await?.len();
+    let dimensions = image.as_ref().map(|image| (image.width, image.height));
+    if let Some(image) = image {
+        let thumbnail_path = files_path.join(images::thumbnail_name(&hash));
+        if !tokio::fs::try_exists(&thumbnail_path).await? {
+            tokio::fs::write(thumbnail_path, image.thumbnail).await?;
+        }
+    }
+
+    let id = db::insert_file(
+        db,
+        &name,
+        &hash,
+        &upload.username,
+        size as i64,
+        Utc::now(),
+        dimensions,
+    )
+    .await?;
+    event!(Level::INFO, "Stored upload {id}: {name} ({size} bytes) as {hash}");
+    update_bytes_stored(db).await?;
+
+    Ok(content::RawJson(
+        serde_json::json!({
+            "id": id,
+            "name": name,
+            "size": size,
+            "width": dimensions.map(|(width, _)| width),
+            "height": dimensions.map(|(_, height)| height),
+        })
+        .to_string(),
+    ))
+}
+
+/// Download a file uploaded through `POST /api/files`.
+#[get("/files/<id>")]
+pub async fn download(id: i64, db: &State<SqlitePool>) -> Result<Download, ApiError> {
+    let file = db::get_file(db, id).await?;
+
+    let path = env::current_dir()?.join("files").join(file.blob_name());
+    // the type comes from the content, the name is whatever the uploader chose
+    let mut head = Vec::new();
+    tokio::fs::File::open(&path).await?.take(SNIFF_LEN).read_to_end(&mut head).await?;
+    let mime = rust_chat::mime::detect(&head);
+    let (content_type, kind) = if INLINE_TYPES.contains(&mime) {
+        (ContentType::parse_flexible(mime).unwrap_or(ContentType::Binary), "inline")
+    } else {
+        (ContentType::Binary, "attachment")
+    };
+    Ok(Download {
+        inner: NamedFile::open(path).await?,
+        content_type,
+        disposition: Header::new("Content-Disposition", content_disposition(kind, &file.name)),
+        nosniff: Header::new("X-Content-Type-Options", "nosniff"),
+    })
+}
+
+/// Download the thumbnail of an image uploaded through `POST /api/files`.
+#[get("/files/<id>/thumbnail")]
+pub async fn thumbnail(
+    id: i64,
+    db: &State<SqlitePool>,
+) -> Result<(ContentType, NamedFile), ApiError> {
+    let file = db::get_file(db, id).await?;
+    let thumbnail_name = file
+        .thumbnail_name()
+        .ok_or(ApiError::NotFound(format!("Thumbnail for file {id}")))?;
+
+    let files_path = env::current_dir()?.join("files");
+    Ok((ContentType::JPEG, NamedFile::open(files_path.join(thumbnail_name)).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use clap::Parser;
+
+    use crate::db::tests::test_db;
+    use crate::error::ApiError;
+    use crate::files::{check_quota, content_disposition, MIB};
+    use crate::{db, Config};
+
+    #[tokio::test]
+    async fn test_check_quota() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let config = Config::parse_from(["web", "--storage-quota-mb", "3"]);
+        let alice = Some(String::from("alice"));
+        let bob = Some(String::from("bob"));
+        db::insert_file(&db, "a.bin", "aaaa", &alice, MIB, Utc::now(), None).await?;
+
+        check_quota(&db, 2 * MIB, &config).await?;
+        assert!(matches!(
+            check_quota(&db, 2 * MIB + 1, &config).await,
+            Err(ApiError::QuotaExceeded(_))
+        ));
+        // the quota is shared, bob's upload counts against alice's next one too
+        db::insert_file(&db, "b.bin", "bbbb", &bob, 2 * MIB, Utc::now(), None).await?;
+        assert!(matches!(
+            check_quota(&db, 1, &config).await,
+            Err(ApiError::QuotaExceeded(_))
+        ));
+
+        let unlimited = Config::parse_from(["web", "--storage-quota-mb", "0"]);
+        check_quota(&db, 100 * MIB, &unlimited).await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("attachment", "notes.txt"),
+            "attachment; filename=\"notes.txt\"; filename*=UTF-8''notes.txt"
+        );
+        assert_eq!(
+            content_disposition("inline", "café photo.jpg"),
+            "inline; filename=\"caf_ photo.jpg\"; filename*=UTF-8''caf%C3%A9%20photo.jpg"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageFormat};
+
+use crate::error::ApiError;
+
+/// Longest side, in pixels, of the thumbnails shown in the chat.
+pub const THUMBNAIL_SIZE: u32 = 320;
+
+/// JPEG quality used when re-encoding photos and thumbnails.
+const JPEG_QUALITY: u8 = 85;
+
+/// Name of the thumbnail for the blob `blob_name` in the `files` directory.
+pub fn thumbnail_name(blob_name: &str) -> String {
+    format!("{blob_name}.thumbnail.jpg")
+}
+
+/// An uploaded image, after its metadata has been stripped.
+#[derive(Debug)]
+pub struct ProcessedImage {
+    pub width: u32,
+    pub height: u32,
+    /// A JPEG no larger than `THUMBNAIL_SIZE` on either side.
+    pub thumbnail: Vec<u8>,
+}
+
+/// If the file at `path` is an image, re-encode it in place and make a thumbnail of it.
+///
+/// Only the pixels survive decoding, so re-encoding drops EXIF data, including GPS location.
+/// Returns `None` for files that aren't images, which are left untouched.
+pub fn process_image(path: &Path) -> Result<Option<ProcessedImage>, ApiError> {
+    let data = fs::read(path)?;
+    let format = match image::guess_format(&data) {
+        Ok(format) => format,
+        Err(_) => return Ok(None),
+    };
+    let image = image::load_from_memory_with_format(&data, format)?;
+
+    // keep the original format where we can, so photos don't balloon in size
+    let format = if format.writing_enabled() {
+        format
+    } else {
+        ImageFormat::Png
+    };
+    fs::write(path, encode(&image, format)?)?;
+
+    Ok(Some(ProcessedImage {
+        width: image.width(),
+        height: image.height(),
+        thumbnail: encode(&image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE), ImageFormat::Jpeg)?,
+    }))
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ApiError> {
+    let mut bytes = Vec::new();
+    match format {
+        ImageFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let encoder = JpegEncoder::new_with_quality(&mut bytes, JPEG_QUALITY);
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        format => image.write_to(&mut Cursor::new(&mut bytes), format)?,
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+    use std::fs;
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    use crate::images::{process_image, THUMBNAIL_SIZE};
+
+    #[test]
+    fn test_process_image() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("lesson-18-test-{}.png", std::process::id()));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(1000, 500))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+        fs::write(&path, png)?;
+
+        let processed = process_image(&path)?.expect("a PNG is an image");
+        assert_eq!((processed.width, processed.height), (1000, 500));
+        let thumbnail = image::load_from_memory(&processed.thumbnail)?;
+        assert_eq!(thumbnail.width(), THUMBNAIL_SIZE);
+        assert_eq!(thumbnail.height(), THUMBNAIL_SIZE / 2);
+
+        fs::write(&path, "not an image")?;
+        assert!(process_image(&path)?.is_none());
+        fs::remove_file(path)?;
+        Ok(())
+    }
+}
@@ -1,39 +1,149 @@
 #[macro_use]
 extern crate rocket;
 
-use std::{env, io};
-use std::fs::{create_dir_all, File};
-use std::path::Path;
-use std::sync::Arc;
+use std::env;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
-use rocket::Config;
-use rocket::fs::{FileServer, NamedFile, relative};
+use rocket::data::{Limits, ToByteUnit};
+use rocket::fairing::AdHoc;
+use rocket::fs::{NamedFile, relative};
 use rocket::futures::TryStreamExt;
 use rocket::response::content;
+use rocket::State;
 use rocket_prometheus::PrometheusMetrics;
+use rust_chat::config::{ConfigError, Loader, Validate};
+use rust_chat::logging::{self, LogArgs, LogHandle};
+use rust_chat::otlp;
+use rust_chat::ratelimit;
 use sqlx::{migrate::MigrateDatabase, Row, Sqlite, SqlitePool};
 use tracing::{event, Level};
-use tracing::level_filters::LevelFilter;
-use tracing_subscriber::{Layer, Registry};
-use tracing_subscriber::layer::SubscriberExt;
 
+mod admin;
+mod db;
+mod error;
+mod files;
+mod images;
 mod message;
+mod retention;
 mod ws;
 
-/// Struct for parsing args.
-#[derive(Parser, Debug)]
-struct Args {
+use error::ApiError;
+
+/// How often to check the config file for changes.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Server config, from the config file, `CHAT_WEB_*` environment variables, and the command line.
+///
+/// This is loaded once on startup, and managed by Rocket for handlers to use.
+#[derive(Parser, Debug, Clone)]
+struct Config {
+    /// TOML config file, with settings named after the arguments, e.g. `db_path = "chat.db"`.
+    #[arg(long)]
+    config: Option<PathBuf>,
     #[arg(short, long, default_value_t = String::from("0.0.0.0"))]
     address: String,
     #[arg(short, long, default_value_t = 11111)]
     port: u16,
-    #[arg(long, default_value = "info")]
-    loglevel: LevelFilter,
+    #[command(flatten)]
+    log: LogArgs,
     #[arg(long, default_value_t = String::from("server.log"))]
     logfile: String,
     #[arg(long, default_value_t = String::from("sqlite.db"))]
     db_path: String,
+    /// Maximum size of uploaded files, in MiB.
+    #[arg(long, default_value_t = 10)]
+    max_upload_mb: u64,
+    /// Maximum size of all uploaded files, in MiB, 0 for no limit.
+    #[arg(long, default_value_t = 1024)]
+    storage_quota_mb: u64,
+    /// Delete uploads older than this many days, 0 to keep them forever.
+    #[arg(long, default_value_t = 0)]
+    retention_days: u64,
+    /// Delete the oldest uploads once they use more than this many MiB, 0 for no limit.
+    #[arg(long, default_value_t = 0)]
+    retention_max_mb: u64,
+    /// How often to enforce the upload and message retention policies, in seconds.
+    #[arg(long, default_value_t = 3600)]
+    retention_interval_secs: u64,
+    /// Delete messages older than this many days, 0 to keep them forever.
+    #[arg(long, default_value_t = 0)]
+    message_retention_days: u64,
+    /// Keep at most this many messages, deleting the oldest first, 0 for no limit.
+    #[arg(long, default_value_t = 0)]
+    message_retention_max: u64,
+    /// Token required by the admin API, which is disabled if not set.
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Messages each websocket client may send per minute, 0 for no limit.
+    #[arg(long, default_value_t = 0)]
+    messages_per_minute: u32,
+}
+
+impl Validate for Config {
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (name, value) in [("address", &self.address), ("logfile", &self.logfile), ("db_path", &self.db_path)] {
+            if value.trim().is_empty() {
+                problems.push(format!("{name} must not be empty"));
+            }
+        }
+        if self.max_upload_mb == 0 {
+            problems.push(String::from("max_upload_mb must be at least 1"));
+        }
+        if self.retention_interval_secs == 0 {
+            problems.push(String::from("retention_interval_secs must be at least 1"));
+        }
+        if self.admin_token.as_deref().is_some_and(|token| token.trim().is_empty()) {
+            problems.push(String::from("admin_token must not be empty, leave it unset to disable the admin API"));
+        }
+        problems
+    }
+}
+
+impl Config {
+    /// Whether `other` changes any setting that is only read on startup.
+    fn needs_restart(&self, other: &Config) -> bool {
+        self.address != other.address
+            || self.port != other.port
+            || self.logfile != other.logfile
+            || self.db_path != other.db_path
+            || self.max_upload_mb != other.max_upload_mb
+            || self.storage_quota_mb != other.storage_quota_mb
+            || self.retention_days != other.retention_days
+            || self.retention_max_mb != other.retention_max_mb
+            || self.retention_interval_secs != other.retention_interval_secs
+            || self.message_retention_days != other.message_retention_days
+            || self.message_retention_max != other.message_retention_max
+            || self.admin_token != other.admin_token
+            || self.log.log_format != other.log.log_format
+            || self.log.log_rotation != other.log.log_rotation
+            || self.log.log_max_files != other.log.log_max_files
+            || self.log.no_log_stdout != other.log.no_log_stdout
+            || self.log.otlp_endpoint != other.log.otlp_endpoint
+    }
+
+    /// Apply the settings that can change while running, the log level and rate limit, from a reloaded config.
+    fn reload(&mut self, log: &LogHandle, reloaded: Result<Config, ConfigError>) {
+        let reloaded = match reloaded {
+            Ok(reloaded) => reloaded,
+            Err(e) => {
+                event!(Level::ERROR, "Ignoring invalid config: {e}");
+                return;
+            }
+        };
+        if let Err(e) = log.set_filter(&reloaded.log.loglevel) {
+            event!(Level::ERROR, "Failed to change log level: {e}");
+        }
+        ratelimit::set_messages_per_minute(reloaded.messages_per_minute);
+        if self.needs_restart(&reloaded) {
+            event!(Level::WARN, "Config changed settings which only apply after a restart");
+        }
+        event!(Level::INFO, "Reloaded config, log level {}, {} messages per minute", reloaded.log.loglevel, reloaded.messages_per_minute);
+        *self = reloaded;
+    }
 }
 
 
@@ -45,18 +155,57 @@ async fn index() -> Option<NamedFile> {
 
 
 #[get("/api/users")]
-async fn api_users() -> content::RawJson<String> {
-    let args = Args::parse();
-    let db = Arc::new(SqlitePool::connect(&args.db_path).await.unwrap());
-
-    let mut users_data = sqlx::query("SELECT username FROM messages").fetch(&*db);
+async fn api_users(db: &State<SqlitePool>) -> Result<content::RawJson<String>, ApiError> {
+    let mut users_data =
+        sqlx::query("SELECT DISTINCT username FROM messages WHERE username IS NOT NULL")
+            .fetch(db.inner());
 
     let mut users: Vec<String> = Vec::new();
-    while let Some(row) = users_data.try_next().await.unwrap() {
-        users.push(row.try_get("username").unwrap());
+    while let Some(row) = users_data.try_next().await? {
+        users.push(row.try_get("username")?);
     }
 
-    content::RawJson(serde_json::to_string(&users).unwrap())
+    // serializing a list of strings can't fail
+    Ok(content::RawJson(serde_json::to_string(&users).unwrap()))
+}
+
+#[get("/api/messages?<before>&<limit>")]
+async fn api_messages(
+    before: Option<i64>,
+    limit: Option<i64>,
+    db: &State<SqlitePool>,
+) -> Result<content::RawJson<String>, ApiError> {
+    let limit = limit.unwrap_or(100);
+    if !(1..=500).contains(&limit) {
+        return Err(ApiError::Validation(String::from(
+            "limit must be between 1 and 500.",
+        )));
+    }
+    let messages = db::history(db, before, limit).await?;
+    // serializing plain data can't fail
+    Ok(content::RawJson(serde_json::to_string(&messages).unwrap()))
+}
+
+#[get("/api/messages/<id>/thread")]
+async fn api_thread(id: i64, db: &State<SqlitePool>) -> Result<content::RawJson<String>, ApiError> {
+    let messages = db::thread(db, id).await?;
+    // serializing plain data can't fail
+    Ok(content::RawJson(serde_json::to_string(&messages).unwrap()))
+}
+
+// ranked below `delete_user`, which would otherwise collide on `/api/users/delete/unread`
+#[get("/api/users/<user>/unread", rank = 2)]
+async fn api_unread(user: &str, db: &State<SqlitePool>) -> Result<content::RawJson<String>, ApiError> {
+    let last_read = ws::last_read(user);
+    let unread = db::unread_count(db, user, last_read).await?;
+    Ok(content::RawJson(
+        serde_json::json!({
+            "username": user,
+            "last_read": last_read,
+            "unread": unread,
+        })
+        .to_string(),
+    ))
 }
 
 #[get("/users")]
@@ -66,74 +215,151 @@ async fn users_page() -> Option<NamedFile> {
 }
 
 #[get("/api/users/delete/<user>")]
-async fn delete_user(user: &str) {
-    let args = Args::parse();
-    let db = Arc::new(SqlitePool::connect(&args.db_path).await.unwrap());
-
-    sqlx::query("DELETE FROM messages WHERE username=$1").bind(user).execute(&*db).await.unwrap();
+async fn delete_user(user: &str, db: &State<SqlitePool>) -> Result<(), ApiError> {
+    if user.trim().is_empty() {
+        return Err(ApiError::Validation(String::from("Username must not be empty.")));
+    }
+    let result = sqlx::query("DELETE FROM messages WHERE username=$1")
+        .bind(user)
+        .execute(db.inner())
+        .await?;
+    if result.rows_affected() == 0 {
+        return Err(ApiError::NotFound(format!("User \"{user}\"")));
+    }
+    Ok(())
 }
 
 
 
 #[launch]
 async fn rocket() -> _ {
-    let args = Args::parse();
+    let loader = Loader::new("CHAT_WEB_");
+    let config: Config = loader.load().unwrap_or_else(|e| e.exit());
     let local_path = env::current_dir().unwrap();
-    let log_subscriber = Registry::default().with({
-        let file =
-            File::create(local_path.join(args.logfile)).expect("Failed to create logfile...");
-        tracing_subscriber::fmt::layer()
-            .with_writer(file)
-            .with_writer(io::stdout)
-            .with_filter(args.loglevel)
-    });
-    tracing::subscriber::set_global_default(log_subscriber)
-        .expect("Unable to set global subscriber...");
+    let log = logging::init(&config.log, &local_path.join(&config.logfile), "web").expect("Failed to set up logging...");
+    ratelimit::set_messages_per_minute(config.messages_per_minute);
+    let mut current = config.clone();
+    loader
+        .watch(CONFIG_POLL_INTERVAL, move |reloaded| current.reload(&log, reloaded))
+        .unwrap_or_else(|e| e.exit());
     let files_path = local_path.join("files");
-    let images_path = files_path.join("images");
 
     event!(Level::INFO, "Creating file storage directories...");
     // want to panic here if we can't create the directories, these are required
-    create_dir_all(images_path.clone()).expect("Failed to create directories to store files...");
+    create_dir_all(&files_path).expect("Failed to create directories to store files...");
     event!(Level::INFO, "Directories created...");
 
-    if !Sqlite::database_exists(&args.db_path)
+    if !Sqlite::database_exists(&config.db_path)
         .await
         .unwrap_or(false)
     {
-        event!(Level::INFO, "Creating message database: {}", &args.db_path);
-        Sqlite::create_database(&args.db_path)
+        event!(Level::INFO, "Creating message database: {}", &config.db_path);
+        Sqlite::create_database(&config.db_path)
             .await
             .expect("Unable to create message database.");
     } else {
-        event!(Level::INFO, "Message database exists: {}", &args.db_path);
+        event!(Level::INFO, "Message database exists: {}", &config.db_path);
+    }
+    let db = SqlitePool::connect(&config.db_path)
+        .await
+        .expect("Unable to connect to message database.");
+    db::setup(&db).await.expect("Failed to set up database.");
+    files::update_bytes_stored(&db)
+        .await
+        .expect("Failed to read stored file sizes.");
+
+    let message_retention = retention::RetentionPolicy {
+        max_age: (config.message_retention_days > 0)
+            .then(|| chrono::Duration::days(config.message_retention_days as i64)),
+        max_messages: (config.message_retention_max > 0)
+            .then_some(config.message_retention_max as i64),
+    };
+    if message_retention.is_enabled() {
+        event!(Level::INFO, "Pruning messages with {message_retention:?}");
+        tokio::spawn(retention::retention_task(
+            db.clone(),
+            message_retention,
+            Duration::from_secs(config.retention_interval_secs),
+        ));
+    }
+
+    let retention = files::RetentionPolicy {
+        max_age: (config.retention_days > 0)
+            .then(|| chrono::Duration::days(config.retention_days as i64)),
+        max_bytes: (config.retention_max_mb > 0)
+            .then(|| config.retention_max_mb as i64 * 1024 * 1024),
+    };
+    if retention.max_age.is_some() || retention.max_bytes.is_some() {
+        event!(Level::INFO, "Enforcing upload retention: {retention:?}");
+        tokio::spawn(files::retention_task(
+            db.clone(),
+            files_path,
+            retention,
+            Duration::from_secs(config.retention_interval_secs),
+        ));
     }
-    let db = Arc::new(SqlitePool::connect(&args.db_path).await.unwrap());
-    sqlx::query(
-        "CREATE TABLE IF NOT EXISTS messages \
-    (\
-        id INTEGER PRIMARY KEY NOT NULL, \
-        username VARCHAR(250), \
-        message VARCHAR(250) NOT NULL\
-    );",
-    )
-    .execute(&*db)
-    .await
-    .expect("Failed to set up database.");
 
     let prometheus = PrometheusMetrics::new();
-    prometheus.registry().register(Box::new(ws::MESSAGES_GAUGE.clone())).unwrap();
-    prometheus.registry().register(Box::new(ws::TEXT_GAUGE.clone())).unwrap();
-    prometheus.registry().register(Box::new(ws::PHOTOS_GAUGE.clone())).unwrap();
-    prometheus.registry().register(Box::new(ws::FILES_GAUGE.clone())).unwrap();
-    let chat_figment = Config::figment()
-        .merge(("port", args.port))
-        .merge(("address", args.address.clone()));
+    prometheus.registry().register(Box::new(ws::MESSAGES_COUNTER.clone())).unwrap();
+    prometheus.registry().register(Box::new(ws::MESSAGE_SIZE_HISTOGRAM.clone())).unwrap();
+    prometheus.registry().register(Box::new(ws::DB_WRITE_HISTOGRAM.clone())).unwrap();
+    prometheus.registry().register(Box::new(ws::CONNECTED_CLIENTS_GAUGE.clone())).unwrap();
+    prometheus.registry().register(Box::new(ws::BROADCAST_LAG_COUNTER.clone())).unwrap();
+    prometheus.registry().register(Box::new(ws::BROADCAST_SKIPPED_COUNTER.clone())).unwrap();
+    prometheus.registry().register(Box::new(files::BYTES_STORED_GAUGE.clone())).unwrap();
+    prometheus.registry().register(Box::new(retention::MESSAGES_PRUNED_COUNTER.clone())).unwrap();
+    let upload_limit = config.max_upload_mb.mebibytes();
+    let chat_figment = rocket::Config::figment()
+        .merge(("port", config.port))
+        .merge(("address", config.address.clone()))
+        .merge((
+            "limits",
+            Limits::default()
+                .limit("file", upload_limit)
+                // leave some room for the other form fields
+                .limit("data-form", upload_limit + 1.mebibytes()),
+        ));
 
     rocket::build()
         .configure(chat_figment)
+        .manage(config)
+        .manage(db)
         .attach(prometheus.clone())
-        .mount("/", routes![index, ws::chat_ws, api_users, users_page, delete_user])
-        .mount("/files", FileServer::from(files_path))
+        .attach(AdHoc::on_shutdown("Export remaining spans", |_| {
+            Box::pin(async {
+                if let Err(e) = otlp::shutdown() {
+                    event!(Level::WARN, "Failed to export remaining spans: {e}");
+                }
+            })
+        }))
+        .mount(
+            "/",
+            routes![
+                index,
+                ws::chat_ws,
+                api_users,
+                api_messages,
+                api_thread,
+                api_unread,
+                users_page,
+                delete_user,
+                files::upload,
+                files::download,
+                files::thumbnail,
+                admin::list_files,
+                admin::purge_file,
+                admin::purge_user_files
+            ],
+        )
         .mount("/metrics", prometheus)
+        .register(
+            "/",
+            catchers![
+                error::forbidden,
+                error::not_found,
+                error::payload_too_large,
+                error::unprocessable_entity,
+                error::internal_error
+            ],
+        )
 }
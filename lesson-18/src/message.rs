@@ -1,18 +1,22 @@
-use std::{fs, io};
-use std::io::Cursor;
-use std::path::Path;
-
-use image::{DynamicImage, ImageFormat};
-use image::io::Reader as ImageReader;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use thiserror::Error;
 
 /// Struct for handling messages from a specific user.
+///
+/// `id` and `timestamp` are assigned by the server on receipt, anything sent by a client is overwritten.
+/// `reply_to` is the id of the message being replied to, if any.
 #[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
 pub struct UserMessage {
+    #[serde(default)]
+    pub id: Option<i64>,
+    #[serde(default)]
+    pub timestamp: Option<DateTime<Utc>>,
     pub username: Option<String>,
     pub message: Message,
+    #[serde(default)]
+    pub reply_to: Option<i64>,
 }
 
 /// Struct for handling different message types.
@@ -25,19 +29,45 @@ pub struct UserMessage {
 /// ```
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum Message {
-    File { name: String, data: Vec<u8> },
-    Photo { data: Vec<u8> },
+    /// A file uploaded through `POST /api/files`.
+    File { file_id: i64, name: String },
+    /// An image uploaded through `POST /api/files`.
+    Photo { file_id: i64 },
     Text(String),
+    Edit { id: i64, new_text: String },
+    Delete { id: i64 },
+    React { message_id: i64, emoji: String },
+    /// Ephemeral, the sender is typing a message.
+    Typing,
+    /// Ephemeral, the sender has read all messages up to and including `up_to_id`.
+    Read { up_to_id: i64 },
+}
+
+impl Message {
+    /// The name of the message variant, as stored in the `kind` column of the DB.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::File { .. } => "File",
+            Message::Photo { .. } => "Photo",
+            Message::Text(_) => "Text",
+            Message::Edit { .. } => "Edit",
+            Message::Delete { .. } => "Delete",
+            Message::React { .. } => "React",
+            Message::Typing => "Typing",
+            Message::Read { .. } => "Read",
+        }
+    }
+
+    /// Whether this message is only relayed to other clients, without being stored.
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, Message::Typing | Message::Read { .. })
+    }
 }
 
 #[derive(Error, Debug)]
 pub enum MessageError {
-    #[error("File {0} not found.")]
-    FileNotFound(String),
-    #[error("Failed to read from file.")]
-    FileReadFailed(#[from] io::Error),
-    #[error("Unsupported image format.")]
-    UnsupportedImage(#[from] image::ImageError),
+    #[error("{0} is not supported, upload the file to /api/files and send its id instead.")]
+    UploadRequired(String),
 }
 
 impl TryFrom<String> for Message {
@@ -46,34 +76,10 @@ impl TryFrom<String> for Message {
     fn try_from(value: String) -> Result<Self, Self::Error> {
         if value.starts_with(".") {
             // handle command
-            let split_data: Vec<_> = value.splitn(2, " ").collect();
-            return match split_data[0] {
-                ".file" => {
-                    let filename = split_data[1];
-                    let file_path = Path::new(filename);
-                    if !file_path.exists() {
-                        return Err(MessageError::FileNotFound(
-                            file_path.to_string_lossy().to_string(),
-                        ));
-                    }
-                    return Ok(Message::File {
-                        // Unwrap is fine here, we've already checked that it exists
-                        name: file_path.file_name().unwrap().to_string_lossy().to_string(),
-                        data: fs::read(&filename)?,
-                    });
-                }
-                ".image" => {
-                    let filename = split_data[1];
-                    let file_path = Path::new(filename);
-
-                    let img: DynamicImage = ImageReader::open(file_path)?.decode()?;
-                    let mut buf = Vec::new();
-                    img.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)?;
-                    if file_path.exists() {
-                        return Ok(Message::Photo { data: buf });
-                    };
-                    Ok(Message::Text(value))
-                }
+            let command = value.split(' ').next().unwrap_or_default();
+            return match command {
+                // file data is never sent over the websocket, only a reference to an upload
+                ".file" | ".image" => Err(MessageError::UploadRequired(command.to_string())),
                 _ => Ok(Message::Text(value)),
             };
         }
@@ -85,7 +91,7 @@ impl TryFrom<String> for Message {
 mod tests {
     use std::error::Error;
 
-    use crate::Message;
+    use crate::message::Message;
 
     #[test]
     fn test_text_message() -> Result<(), Box<dyn Error>> {
@@ -95,4 +101,10 @@ mod tests {
         assert_eq!(message, expected);
         Ok(())
     }
+
+    #[test]
+    fn test_file_command_requires_upload() {
+        let value = String::from(".file notes.txt");
+        assert!(Message::try_from(value).is_err());
+    }
 }
@@ -0,0 +1,115 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use prometheus::{opts, register_int_counter_vec, IntCounterVec};
+use sqlx::SqlitePool;
+use tracing::{event, Level};
+
+use crate::db::{self, PruneStats};
+use crate::error::ApiError;
+
+lazy_static! {
+    pub static ref MESSAGES_PRUNED_COUNTER: IntCounterVec = register_int_counter_vec!(opts!("messages_pruned_total", "The total number of messages deleted by the retention policy."), &["reason"]).unwrap();
+}
+
+/// Which messages `retention_task` deletes, `None` disables a limit.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Delete messages older than this.
+    pub max_age: Option<chrono::Duration>,
+    /// Keep at most this many messages, deleting the oldest first.
+    pub max_messages: Option<i64>,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.max_age.is_some() || self.max_messages.is_some()
+    }
+}
+
+/// Prune messages according to `policy` once, counting them in `MESSAGES_PRUNED_COUNTER`.
+async fn prune(db: &SqlitePool, policy: &RetentionPolicy) -> Result<PruneStats, ApiError> {
+    let cutoff = policy.max_age.map(|max_age| Utc::now() - max_age);
+    let stats = db::prune_messages(db, cutoff, policy.max_messages).await?;
+    MESSAGES_PRUNED_COUNTER
+        .with_label_values(&["age"])
+        .inc_by(stats.by_age);
+    MESSAGES_PRUNED_COUNTER
+        .with_label_values(&["count"])
+        .inc_by(stats.by_count);
+    Ok(stats)
+}
+
+/// Prune messages according to `policy` every `interval`, until the server stops.
+pub async fn retention_task(db: SqlitePool, policy: RetentionPolicy, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        match prune(&db, &policy).await {
+            Ok(stats) if stats.by_age > 0 || stats.by_count > 0 => {
+                event!(
+                    Level::INFO,
+                    "Pruned {} messages by age and {} by count",
+                    stats.by_age,
+                    stats.by_count
+                );
+            }
+            Ok(_) => {}
+            Err(e) => event!(Level::ERROR, "Failed to prune messages: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use crate::db;
+    use crate::db::tests::{send, test_db};
+    use crate::error::ApiError;
+    use crate::message::{Message, UserMessage};
+    use crate::retention::{self, RetentionPolicy, MESSAGES_PRUNED_COUNTER};
+
+    #[test]
+    fn test_policy_is_enabled() {
+        let disabled = RetentionPolicy { max_age: None, max_messages: None };
+        assert!(!disabled.is_enabled());
+        let by_count = RetentionPolicy { max_age: None, max_messages: Some(10) };
+        assert!(by_count.is_enabled());
+        let by_age = RetentionPolicy { max_age: Some(Duration::days(1)), max_messages: None };
+        assert!(by_age.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_prune_counts_messages() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let old = UserMessage {
+            id: None,
+            timestamp: Some(Utc::now() - Duration::days(2)),
+            username: Some(String::from("alice")),
+            message: Message::Text(String::from("old")),
+            reply_to: None,
+        };
+        db::insert_message(&db, &old, "old", None).await?;
+        for text in ["one", "two", "three"] {
+            send(&db, Some("alice"), text, None).await?;
+        }
+        let by_age = MESSAGES_PRUNED_COUNTER.with_label_values(&["age"]).get();
+        let by_count = MESSAGES_PRUNED_COUNTER.with_label_values(&["count"]).get();
+
+        let policy = RetentionPolicy {
+            max_age: Some(Duration::days(1)),
+            max_messages: Some(2),
+        };
+        retention::prune(&db, &policy).await?;
+        assert_eq!(MESSAGES_PRUNED_COUNTER.with_label_values(&["age"]).get() - by_age, 1);
+        assert_eq!(MESSAGES_PRUNED_COUNTER.with_label_values(&["count"]).get() - by_count, 1);
+        assert_eq!(db::history(&db, None, 10).await?.len(), 2);
+
+        // nothing left to prune
+        let stats = retention::prune(&db, &policy).await?;
+        assert_eq!((stats.by_age, stats.by_count), (0, 0));
+        Ok(())
+    }
+}
@@ -1,117 +1,455 @@
-use std::env;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 use chrono::Utc;
-use clap::Parser;
-use prometheus::{Gauge, opts, register_gauge};
+use prometheus::{exponential_buckets, histogram_opts, opts, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge, HistogramVec, IntCounter, IntCounterVec, IntGauge};
+use rocket::State;
 use rocket::futures::{SinkExt, stream::SplitSink, stream::SplitStream, StreamExt, TryStreamExt};
 use rocket_ws::Message as WSMessage;
 use rocket_ws::stream::DuplexStream;
+use rust_chat::ratelimit::{self, RateLimiter};
+use serde::Serialize;
 use sqlx::SqlitePool;
-use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::select;
+use tokio::sync::broadcast::{channel, error::RecvError, Receiver, Sender};
 use tracing::{event, Level};
 
+use crate::db;
+use crate::error::ApiError;
 use crate::message::{Message, UserMessage};
 
 use rocket_ws as ws;
 use lazy_static::lazy_static;
-use crate::Args;
 
 lazy_static! {
     static ref BROADCAST: (Sender<(String, UserMessage)>, Receiver<(String, UserMessage)>) = channel(1024);
-    pub static ref MESSAGES_GAUGE: Gauge = register_gauge!(opts!("messages_sent", "The total number of messages sent, including text, photos, and files.")).unwrap();
-    pub static ref TEXT_GAUGE: Gauge = register_gauge!(opts!("text_messages_sent", "The total number of text messages sent.")).unwrap();
-    pub static ref PHOTOS_GAUGE: Gauge = register_gauge!(opts!("photos_sent", "The total number of photos sent.")).unwrap();
-    pub static ref FILES_GAUGE: Gauge = register_gauge!(opts!("files_sent", "The total number of files sent.")).unwrap();
+    pub static ref MESSAGES_COUNTER: IntCounterVec = register_int_counter_vec!(opts!("messages_sent_total", "The total number of messages sent, by kind and room."), &["kind", "room"]).unwrap();
+    pub static ref MESSAGE_SIZE_HISTOGRAM: HistogramVec = register_histogram_vec!(histogram_opts!("message_size_bytes", "Size of received websocket messages, by kind.", exponential_buckets(32.0, 4.0, 8).unwrap()), &["kind"]).unwrap();
+    pub static ref DB_WRITE_HISTOGRAM: HistogramVec = register_histogram_vec!(histogram_opts!("db_write_duration_seconds", "Time taken to write messages to the DB, by operation."), &["operation"]).unwrap();
+    pub static ref CONNECTED_CLIENTS_GAUGE: IntGauge = register_int_gauge!(opts!("connected_clients", "The number of currently connected websocket clients.")).unwrap();
+    pub static ref BROADCAST_LAG_COUNTER: IntCounter = register_int_counter!(opts!("broadcast_lag_events_total", "The number of times a client fell behind the broadcast channel.")).unwrap();
+    pub static ref BROADCAST_SKIPPED_COUNTER: IntCounter = register_int_counter!(opts!("broadcast_skipped_messages_total", "The number of messages clients missed by falling behind the broadcast channel.")).unwrap();
+    /// Id of the last message each user has read, kept in memory only.
+    pub static ref LAST_READ: Mutex<HashMap<String, i64>> = Mutex::new(HashMap::new());
 }
 
-async fn ws_recv(key: String, mut recv: SplitStream<DuplexStream>) -> anyhow::Result<()> {
+/// There is a single chat room, named after its websocket route, used to label metrics.
+const ROOM: &str = "chat";
+
+/// Time a DB write, recording it in `DB_WRITE_HISTOGRAM` under `operation`.
+async fn timed_write<T>(operation: &str, write: impl Future<Output = T>) -> T {
+    let timer = DB_WRITE_HISTOGRAM
+        .with_label_values(&[operation])
+        .start_timer();
+    let result = write.await;
+    timer.observe_duration();
+    result
+}
+
+/// Frame sent to websocket clients.
+///
+/// Senders also receive their own messages, with `own` set, so they learn the id and timestamp the server assigned.
+#[derive(Serialize)]
+struct OutgoingFrame<'a> {
+    own: bool,
+    #[serde(flatten)]
+    message: &'a UserMessage,
+}
+
+async fn ws_recv(key: String, db: SqlitePool, mut recv: SplitStream<DuplexStream>) -> anyhow::Result<()> {
     let broadcast = BROADCAST.0.clone();
-    loop {
-        if let Ok(data) = recv.try_next().await {
-            if data.is_none() {
+    let mut limiter = RateLimiter::new();
+    let mut username = None;
+    while let Some(data) = recv.try_next().await? {
+        let text = match data {
+            WSMessage::Text(text) => text,
+            WSMessage::Close(frame) => {
+                event!(Level::INFO, "Client {key} sent close frame: {frame:?}");
                 return Ok(());
             }
-            let json = serde_json::from_str(data.unwrap().to_text().unwrap());
-            if json.is_ok() {
-                let message: UserMessage = json.unwrap();
-                handle_msg(message.clone()).await;
-                broadcast.send((key.clone(), message)).unwrap();
-            }
+            // pings are answered by the websocket implementation, binary frames are not part of the protocol
+            _ => continue,
+        };
+        if let Some(message) = recv_text(&key, &text, &mut username, &mut limiter, &db).await {
+            // only fails if there are no subscribers, which can't happen while this connection is open
+            let _ = broadcast.send((key.clone(), message));
         }
     }
+    Ok(())
 }
-async fn ws_send(key: String, mut send: SplitSink<DuplexStream, WSMessage>) -> anyhow::Result<()> {
-    let broadcast = BROADCAST.0.clone();
-    loop {
-        if let Ok(data) = broadcast.subscribe().recv().await {
-            if data.0 != key {
-                send.send(rocket_ws::Message::Text(
-                    serde_json::to_string(&data.1).unwrap(),
-                ))
-                .await?; }
+
+/// Handle a text frame from client `key`, returning the message to broadcast, if there is one.
+///
+/// Every message counts towards the rate limit, including typing and read events, which are relayed to every client.
+async fn recv_text(
+    key: &str,
+    text: &str,
+    username: &mut Option<String>,
+    limiter: &mut RateLimiter,
+    db: &SqlitePool,
+) -> Option<UserMessage> {
+    let mut message = match serde_json::from_str::<UserMessage>(text) {
+        Ok(message) => message,
+        Err(e) => {
+            event!(Level::WARN, "Invalid message from client {key}: {e}");
+            return None;
+        }
+    };
+    if let Err(e) = bind_username(username, &mut message) {
+        event!(Level::WARN, "Rejected message from client {key}: {e}");
+        return None;
+    }
+    let kind = message.message.kind();
+    MESSAGE_SIZE_HISTOGRAM
+        .with_label_values(&[kind])
+        .observe(text.len() as f64);
+    if !limiter.allow() {
+        event!(
+            Level::WARN,
+            "Dropped message from client {key}, over {} messages per minute.",
+            ratelimit::MESSAGES_PER_MINUTE.load(Ordering::Relaxed)
+        );
+        return None;
+    }
+    if message.message.is_ephemeral() {
+        return Some(handle_ephemeral(message));
+    }
+    match handle_msg(message, db).await {
+        Ok(message) => {
+            MESSAGES_COUNTER.with_label_values(&[kind, ROOM]).inc();
+            Some(message)
+        }
+        Err(e) if e.status().class().is_server_error() => {
+            event!(Level::ERROR, "Failed to store message from client {key}: {e}");
+            None
+        }
+        Err(e) => {
+            event!(Level::WARN, "Rejected message from client {key}: {e}");
+            None
         }
     }
 }
 
+async fn ws_send(
+    key: String,
+    mut send: SplitSink<DuplexStream, WSMessage>,
+    mut broadcast: Receiver<(String, UserMessage)>,
+) -> anyhow::Result<()> {
+    loop {
+        match broadcast.recv().await {
+            Ok((sender, message)) => {
+                let frame = OutgoingFrame {
+                    own: sender == key,
+                    message: &message,
+                };
+                send.send(WSMessage::Text(serde_json::to_string(&frame)?))
+                    .await?;
+            }
+            Err(RecvError::Lagged(skipped)) => {
+                BROADCAST_LAG_COUNTER.inc();
+                BROADCAST_SKIPPED_COUNTER.inc_by(skipped);
+                event!(Level::WARN, "Client {key} lagged behind, skipped {skipped} messages.");
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
 
 #[get("/ws/chat")]
-pub async fn chat_ws(ws: ws::WebSocket) -> ws::Channel<'static> {
+pub async fn chat_ws(ws: ws::WebSocket, db: &State<SqlitePool>) -> ws::Channel<'static> {
     let key = ws.accept_key().to_string();
+    // the pool is shared, cloning it only clones a handle
+    let db = db.inner().clone();
     ws.channel(move |stream| {
         Box::pin(async move {
+            // subscribe once, before anything is read, so no messages are missed
+            let subscription = BROADCAST.0.subscribe();
             let (send, recv) = stream.split();
-            let recv = tokio::spawn(ws_recv(key.clone(), recv));
-            let send = tokio::spawn(ws_send(key.clone(), send));
 
-            tokio::join!(recv, send);
+            CONNECTED_CLIENTS_GAUGE.inc();
+            event!(Level::INFO, "Client {key} connected.");
+
+            // if either of these return, they should both be stopped
+            let result = select! {
+                r = ws_recv(key.clone(), db, recv) => r,
+                r = ws_send(key.clone(), send, subscription) => r,
+            };
+
+            CONNECTED_CLIENTS_GAUGE.dec();
+            match result {
+                Ok(()) => event!(Level::INFO, "Client {key} disconnected."),
+                Err(e) => event!(Level::WARN, "Client {key} disconnected: {e}"),
+            }
 
             Ok(())
         })
     })
 }
 
-async fn handle_msg(message: UserMessage) {
-    let local_path = env::current_dir().unwrap();
-    let files_path = local_path.join("files");
-    let images_path = files_path.join("images");
-    let username = message.username.unwrap_or("Anonymous".to_string());
-    MESSAGES_GAUGE.inc();
-    match message.message {
-        Message::File { name, data } => {
-            FILES_GAUGE.inc();
-            event!(Level::INFO, "Receiving file from \"{username}\": {name}...");
-            tokio::fs::write(files_path.clone().join(name), data)
-                .await
-                .expect("Failed to write received file...");
+/// Check a message against the username bound to its connection, binding the first one the client sends.
+///
+/// Edits, deletes and reads are checked against the message's username, so without this a client could act as any
+/// user just by naming them. Messages without a username are sent as the bound user, once there is one.
+fn bind_username(bound: &mut Option<String>, message: &mut UserMessage) -> Result<(), ApiError> {
+    match (bound.as_ref(), message.username.as_ref()) {
+        (None, _) => *bound = message.username.clone(),
+        (Some(_), None) => message.username = bound.clone(),
+        (Some(bound), Some(username)) if bound != username => {
+            return Err(ApiError::Forbidden(format!(
+                "This connection belongs to \"{bound}\", not \"{username}\"."
+            )));
+        }
+        (Some(_), Some(_)) => {}
+    }
+    Ok(())
+}
+
+/// Handle typing and read events, which are relayed without touching the DB or message metrics.
+fn handle_ephemeral(mut message: UserMessage) -> UserMessage {
+    message.id = None;
+    message.reply_to = None;
+    message.timestamp = Some(Utc::now());
+    if let (Message::Read { up_to_id }, Some(username)) = (&message.message, &message.username) {
+        // a poisoned lock only means another task panicked mid-update, the map itself is still usable
+        let mut last_read = LAST_READ.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = last_read.entry(username.clone()).or_insert(*up_to_id);
+        *entry = (*entry).max(*up_to_id);
+    }
+    message
+}
+
+/// Get the id of the last message a user has read, if they have read any since the server started.
+pub fn last_read(username: &str) -> Option<i64> {
+    LAST_READ
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .get(username)
+        .copied()
+}
+
+/// Store a received message, and stamp it with its DB id and the time it was received.
+///
+/// Edits, deletes and reactions are applied to the message they reference, and are stamped with a timestamp only.
+async fn handle_msg(mut message: UserMessage, db: &SqlitePool) -> Result<UserMessage, ApiError> {
+    let username = message.username.clone().unwrap_or("Anonymous".to_string());
+    let timestamp = Utc::now();
+    message.id = None;
+    message.timestamp = Some(timestamp);
+    if !matches!(
+        message.message,
+        Message::Text(_) | Message::File { .. } | Message::Photo { .. }
+    ) {
+        message.reply_to = None;
+    }
+
+    let (content, file_id) = match &mut message.message {
+        Message::File { file_id, name } => {
+            let file = db::get_file(db, *file_id).await?;
+            event!(Level::INFO, "Receiving file from \"{username}\": {}...", file.name);
+            // the name comes from the upload, so clients can't send a misleading one
+            *name = file.name.clone();
+            (file.name, Some(file.id))
         }
-        Message::Photo { data } => {
-            PHOTOS_GAUGE.inc();
-            println!("Receiving photo from \"{username}\"...");
+        Message::Photo { file_id } => {
+            let file = db::get_file(db, *file_id).await?;
+            if file.width.is_none() {
+                return Err(ApiError::Validation(format!("File {file_id} is not an image.")));
+            }
             event!(Level::INFO, "Receiving photo from \"{username}\"...");
-            let timestamp = Utc::now();
-            tokio::fs::write(
-                images_path
-                    .clone()
-                    .join(format!("{}.png", timestamp.timestamp())),
-                data,
-            )
-            .await
-            .expect("Failed to write received photo...");
+            (file.name, Some(file.id))
+        }
+        Message::Text(text) => {
+            event!(
+                Level::INFO,
+                "Receiving message from \"{username}\": {text}"
+            );
+            (text.clone(), None)
         }
-        Message::Text(message) => {
-            TEXT_GAUGE.inc();
+        Message::Edit { id, new_text } => {
             event!(
                 Level::INFO,
-                "Receiving message from \"{username}\": {message}"
+                "Receiving edit of message {id} from \"{username}\": {new_text}"
             );
-            let args = Args::parse();
-            let db = Arc::new(SqlitePool::connect(&args.db_path).await.unwrap());
-            sqlx::query("INSERT INTO messages (username, message) VALUES ($1, $2)")
-                .bind(&username)
-                .bind(message)
-                .execute(&mut *db.acquire().await.unwrap())
-                .await
-                .unwrap();
+            timed_write("edit", db::edit_message(db, *id, &message.username, new_text)).await?;
+            return Ok(message);
         }
+        Message::React { message_id, emoji } => {
+            event!(
+                Level::INFO,
+                "Receiving reaction {emoji} to message {message_id} from \"{username}\""
+            );
+            let added =
+                timed_write("react", db::add_reaction(db, *message_id, &message.username, emoji))
+                    .await?;
+            if !added {
+                return Err(ApiError::Validation(format!(
+                    "Already reacted to message {message_id} with {emoji}."
+                )));
+            }
+            return Ok(message);
+        }
+        // relayed by handle_ephemeral, never stored
+        Message::Typing | Message::Read { .. } => return Ok(message),
+        Message::Delete { id } => {
+            event!(
+                Level::INFO,
+                "Receiving delete of message {id} from \"{username}\""
+            );
+            timed_write("delete", db::delete_message(db, *id, &message.username)).await?;
+            return Ok(message);
+        }
+    };
+    let id = timed_write(
+        "insert",
+        db::insert_message(db, &message, &content, file_id),
+    )
+    .await?;
+
+    message.id = Some(id);
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rust_chat::ratelimit::{self, RateLimiter};
+
+    use crate::db::tests::{send, test_db};
+    use crate::error::ApiError;
+    use crate::message::{Message, UserMessage};
+    use crate::{db, ws};
+
+    fn message(username: Option<&str>, message: Message) -> UserMessage {
+        UserMessage {
+            id: None,
+            timestamp: None,
+            username: username.map(String::from),
+            message,
+            reply_to: None,
+        }
+    }
+
+    #[test]
+    fn test_bind_username() {
+        let mut bound = None;
+        let mut anonymous = message(None, Message::Typing);
+        assert!(ws::bind_username(&mut bound, &mut anonymous).is_ok());
+        assert_eq!(bound, None);
+
+        let mut first = message(Some("alice"), Message::Typing);
+        assert!(ws::bind_username(&mut bound, &mut first).is_ok());
+        assert_eq!(bound.as_deref(), Some("alice"));
+
+        let mut unnamed = message(None, Message::Delete { id: 1 });
+        assert!(ws::bind_username(&mut bound, &mut unnamed).is_ok());
+        assert_eq!(unnamed.username.as_deref(), Some("alice"));
+
+        let mut impostor = message(Some("bob"), Message::Delete { id: 1 });
+        assert!(matches!(
+            ws::bind_username(&mut bound, &mut impostor),
+            Err(ApiError::Forbidden(_))
+        ));
+        assert_eq!(bound.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_covers_typing_events() {
+        let db = test_db().await;
+        let typing = serde_json::to_string(&message(Some("alice"), Message::Typing)).unwrap();
+        let mut username = None;
+        let mut limiter = RateLimiter::new();
+
+        // the limit is shared by every test, but no other test goes through the limiter
+        ratelimit::set_messages_per_minute(2);
+        let mut relayed = 0;
+        for _ in 0..3 {
+            if ws::recv_text("test", &typing, &mut username, &mut limiter, &db).await.is_some() {
+                relayed += 1;
+            }
+        }
+        ratelimit::set_messages_per_minute(0);
+        assert_eq!(relayed, 2);
+    }
+
+    #[test]
+    fn test_read_events_only_move_forward() {
+        // LAST_READ is shared by every test, so use a name no other test does
+        let reader = "test_read_events_only_move_forward";
+        assert_eq!(ws::last_read(reader), None);
+
+        let mut read = message(Some(reader), Message::Read { up_to_id: 5 });
+        read.id = Some(42);
+        read.reply_to = Some(3);
+        let relayed = ws::handle_ephemeral(read);
+        assert_eq!((relayed.id, relayed.reply_to), (None, None));
+        assert!(relayed.timestamp.is_some());
+        assert_eq!(ws::last_read(reader), Some(5));
+
+        ws::handle_ephemeral(message(Some(reader), Message::Read { up_to_id: 2 }));
+        assert_eq!(ws::last_read(reader), Some(5));
+        ws::handle_ephemeral(message(Some(reader), Message::Typing));
+        assert_eq!(ws::last_read(reader), Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_handle_msg_stores_text() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let mut text = message(Some("alice"), Message::Text(String::from("hello")));
+        text.id = Some(100);
+        let stored = ws::handle_msg(text, &db).await?;
+
+        let history = db::history(&db, None, 10).await?;
+        assert_eq!(history.len(), 1);
+        assert_eq!(stored.id, Some(history[0].id));
+        assert!(stored.timestamp.is_some());
+        assert_eq!(history[0].message, "hello");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handle_msg_applies_changes() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let id = send(&db, Some("alice"), "helo", None).await?;
+
+        let mut edit = message(Some("alice"), Message::Edit { id, new_text: String::from("hello") });
+        // only new messages can be replies
+        edit.reply_to = Some(id);
+        let edited = ws::handle_msg(edit, &db).await?;
+        assert_eq!((edited.id, edited.reply_to), (None, None));
+        assert!(edited.timestamp.is_some());
+        assert_eq!(db::history(&db, None, 10).await?[0].message, "hello");
+
+        let react = || message(Some("bob"), Message::React { message_id: id, emoji: String::from("👍") });
+        ws::handle_msg(react(), &db).await?;
+        assert!(matches!(ws::handle_msg(react(), &db).await, Err(ApiError::Validation(_))));
+        assert_eq!(db::history(&db, None, 10).await?[0].reactions.get("👍"), Some(&1));
+
+        let delete = message(Some("bob"), Message::Delete { id });
+        assert!(matches!(ws::handle_msg(delete, &db).await, Err(ApiError::Forbidden(_))));
+        ws::handle_msg(message(Some("alice"), Message::Delete { id }), &db).await?;
+        assert!(db::history(&db, None, 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_handle_msg_uses_upload_names() -> Result<(), ApiError> {
+        let db = test_db().await;
+        let alice = Some(String::from("alice"));
+        let file_id = db::insert_file(&db, "notes.txt", "aaaa", &alice, 10, Utc::now(), None).await?;
+        let file = message(
+            Some("alice"),
+            Message::File { file_id, name: String::from("invoice.pdf") },
+        );
+        let stored = ws::handle_msg(file, &db).await?;
+        assert_eq!(stored.message, Message::File { file_id, name: String::from("notes.txt") });
+        assert_eq!(db::history(&db, None, 10).await?[0].file_id, Some(file_id));
+
+        // only images can be sent as photos
+        let photo = message(Some("alice"), Message::Photo { file_id });
+        assert!(matches!(ws::handle_msg(photo, &db).await, Err(ApiError::Validation(_))));
+        let missing = message(Some("alice"), Message::Photo { file_id: file_id + 1 });
+        assert!(matches!(ws::handle_msg(missing, &db).await, Err(ApiError::NotFound(_))));
+        Ok(())
     }
 }